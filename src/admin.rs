@@ -0,0 +1,448 @@
+//! Admin API server.
+//!
+//! Like the metrics server, it is listening on its own address
+//! to allow exposing it on a private network only.
+//! Requests are authenticated with a bearer token.
+
+use anyhow::{Context as _, Result};
+use axum::extract::{Query, Request};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::Json;
+use log::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+use crate::notifier::{self, Eviction};
+use crate::ratelimit;
+use crate::schedule::TokenRecord;
+use crate::server::{self, AppError, NotificationToken};
+use crate::state::{ProviderHealth, State};
+
+#[derive(Clone)]
+struct AdminState {
+    state: State,
+    eviction: Eviction,
+}
+
+pub async fn start(state: State, server: String, eviction: Eviction) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(server).await?;
+    axum::serve(listener, router(state, eviction)).await?;
+    Ok(())
+}
+
+fn router(state: State, eviction: Eviction) -> axum::Router {
+    let admin_state = AdminState {
+        state: state.clone(),
+        eviction,
+    };
+    axum::Router::new()
+        .route("/status", get(status))
+        .route("/pause", post(pause))
+        .route("/resume", post(resume))
+        .route("/token", get(get_token).delete(remove_token))
+        .route("/token/register", post(register_token))
+        .route("/token/heartbeat", post(heartbeat))
+        .layer(middleware::from_fn_with_state(state, authenticate))
+        .with_state(admin_state)
+}
+
+/// Rejects requests without `Authorization: Bearer <admin token>`.
+async fn authenticate(
+    axum::extract::State(state): axum::extract::State<State>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(admin_token) = state.admin_token() else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| {
+            // Hashes have the same length
+            // and comparing them does not leak the token.
+            Sha256::digest(token.as_bytes()) == Sha256::digest(admin_token.as_bytes())
+        });
+    if !authorized {
+        warn!("Rejecting unauthenticated admin request.");
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(request).await
+}
+
+#[derive(Debug, Serialize)]
+struct Status {
+    /// Whether heartbeat notifications are paused.
+    paused: bool,
+
+    /// Number of scheduled heartbeat tokens.
+    tokens: usize,
+
+    /// Expiry time of the APNS certificate as a Unix timestamp.
+    apns_certificate_expiry: Option<i64>,
+
+    providers: Vec<ProviderHealth>,
+}
+
+/// Returns the state of the heartbeat notifier and provider health.
+async fn status(axum::extract::State(admin): axum::extract::State<AdminState>) -> Json<Status> {
+    let state = &admin.state;
+    Json(Status {
+        paused: state.paused(),
+        tokens: state.schedule().token_count(),
+        apns_certificate_expiry: state.apns_certificate_expiry(),
+        providers: state.provider_health(),
+    })
+}
+
+async fn pause(axum::extract::State(admin): axum::extract::State<AdminState>) -> StatusCode {
+    info!("Pausing heartbeat notifications.");
+    admin.state.set_paused(true);
+    StatusCode::OK
+}
+
+async fn resume(axum::extract::State(admin): axum::extract::State<AdminState>) -> StatusCode {
+    info!("Resuming heartbeat notifications.");
+    admin.state.set_paused(false);
+    StatusCode::OK
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenQuery {
+    /// Token as registered by the client,
+    /// `openpgp:` tokens are decrypted.
+    token: String,
+}
+
+#[derive(Debug, Serialize)]
+struct TokenStatus {
+    token: String,
+
+    record: TokenRecord,
+}
+
+/// Decrypts the token if it is OpenPGP-encrypted.
+fn resolve_token(state: &State, token: &str) -> Result<String> {
+    match token.strip_prefix("openpgp:") {
+        Some(encrypted) => {
+            let decryptor = state
+                .openpgp_decryptor()
+                .context("OpenPGP is not configured")?;
+            Ok(decryptor.decrypt(encrypted)?.token)
+        }
+        None => Ok(token.to_string()),
+    }
+}
+
+/// Returns the stored record of the token,
+/// or 404 if the token is not registered.
+fn token_status(state: &State, token: String) -> Result<Response> {
+    Ok(match state.schedule().get(&token)? {
+        Some(record) => Json(TokenStatus { token, record }).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    })
+}
+
+/// Returns the schedule status of a token.
+async fn get_token(
+    axum::extract::State(admin): axum::extract::State<AdminState>,
+    Query(query): Query<TokenQuery>,
+) -> Result<Response, AppError> {
+    let token = resolve_token(&admin.state, &query.token)?;
+    Ok(token_status(&admin.state, token)?)
+}
+
+/// Removes a token from the schedule.
+async fn remove_token(
+    axum::extract::State(admin): axum::extract::State<AdminState>,
+    Query(query): Query<TokenQuery>,
+) -> Result<Response, AppError> {
+    let token = resolve_token(&admin.state, &query.token)?;
+    let schedule = admin.state.schedule();
+    if schedule.get(&token)?.is_none() {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    info!("Removing token {token} on admin request.");
+    schedule.remove_token(&token)?;
+    schedule.flush().await?;
+    Ok(StatusCode::OK.into_response())
+}
+
+/// Registers a token as if the client registered it,
+/// resetting its failures.
+async fn register_token(
+    axum::extract::State(admin): axum::extract::State<AdminState>,
+    Json(query): Json<TokenQuery>,
+) -> Result<Response, AppError> {
+    let token = resolve_token(&admin.state, &query.token)?;
+    info!("Registering token {token} on admin request.");
+    let schedule = admin.state.schedule();
+    schedule.register_token_now(&token)?;
+    schedule.flush().await?;
+    Ok(token_status(&admin.state, token)?)
+}
+
+/// Sends a heartbeat notification to a registered APNS token immediately
+/// and returns its updated record.
+///
+/// Returns 409 Conflict if the token is being notified,
+/// 429 Too Many Requests if the rate limit is exceeded
+/// and 503 Service Unavailable if tokens are still being loaded.
+async fn heartbeat(
+    axum::extract::State(admin): axum::extract::State<AdminState>,
+    Json(query): Json<TokenQuery>,
+) -> Result<Response, AppError> {
+    let state = &admin.state;
+    let token = resolve_token(state, &query.token)?;
+    let device_token: NotificationToken = token.parse()?;
+    let provider = device_token.provider();
+    match device_token {
+        NotificationToken::ApnsSandbox(_) | NotificationToken::ApnsProduction(_) => {}
+        NotificationToken::Fcm { .. } | NotificationToken::UBports(_) => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                "Heartbeat notifications are only sent to APNS tokens",
+            )
+                .into_response());
+        }
+    }
+    let schedule = state.schedule();
+    if schedule.get(&token)?.is_none() {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    // The token is taken out of the schedule like by the heartbeat notifier,
+    // so both do not notify it at the same time.
+    let rate_limiter = state.rate_limiter();
+    match schedule.take(&token, |_token| {
+        rate_limiter.try_acquire(provider, ratelimit::Priority::Heartbeat)
+    }) {
+        Ok(Some(_timestamp)) => {}
+        Ok(None) if schedule.is_loading() => {
+            // Stored tokens are not scheduled before they are loaded.
+            return Ok((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Tokens are still being loaded",
+            )
+                .into_response());
+        }
+        Ok(None) => {
+            return Ok((StatusCode::CONFLICT, "Token is already being notified").into_response());
+        }
+        Err(delay) => return Ok(server::rate_limited(provider, delay)),
+    }
+    info!("Sending heartbeat to {token} on admin request.");
+    notifier::wakeup(state, state.interval(), admin.eviction, token.clone()).await?;
+    Ok(token_status(state, token)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use crate::config::{Config, Secret};
+    use crate::metrics::Metrics;
+    use crate::schedule::Schedule;
+    use crate::storage::{MemoryStorage, Storage as _};
+
+    const ADMIN_TOKEN: &str = "0123456789abcdef";
+
+    /// Starts the admin API with the tokens of `storage` on a random port
+    /// and returns its URL.
+    ///
+    /// The schedule is not loaded.
+    async fn serve_with(storage: MemoryStorage, config: Config) -> Result<(State, String)> {
+        let config = Config {
+            admin_token: Some(Secret::Value(ADMIN_TOKEN.to_string())),
+            ..config
+        };
+        let schedule = Schedule::with_storage(Box::new(storage));
+        let state = State::new(schedule, Metrics::new(), &config).await?;
+        let eviction = Eviction {
+            max_failures: 3,
+            period: Duration::from_secs(60),
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let app = router(state.clone(), eviction);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Ok((state, url))
+    }
+
+    /// Starts the admin API with an empty loaded schedule.
+    async fn serve() -> Result<(State, String)> {
+        let (state, url) = serve_with(MemoryStorage::default(), Config::default()).await?;
+        state.schedule().load()?;
+        Ok((state, url))
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() -> Result<()> {
+        let (_state, url) = serve().await?;
+        let client = reqwest::Client::new();
+
+        let res = client.get(format!("{url}/status")).send().await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = client
+            .get(format!("{url}/status"))
+            .bearer_auth("wrong-token-0123456789")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = client
+            .get(format!("{url}/status"))
+            .bearer_auth(ADMIN_TOKEN)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let status: serde_json::Value = serde_json::from_str(&res.text().await?)?;
+        assert_eq!(status["paused"], false);
+        assert_eq!(status["tokens"], 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pause_resume() -> Result<()> {
+        let (state, url) = serve().await?;
+        let client = reqwest::Client::new();
+        let mut paused = state.subscribe_paused();
+
+        let res = client.post(format!("{url}/pause")).send().await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(!state.paused());
+
+        let res = client
+            .post(format!("{url}/pause"))
+            .bearer_auth(ADMIN_TOKEN)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(state.paused());
+        assert!(paused.has_changed()?);
+        paused.mark_unchanged();
+
+        let res = client
+            .post(format!("{url}/resume"))
+            .bearer_auth(ADMIN_TOKEN)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!state.paused());
+        assert!(paused.has_changed()?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_token() -> Result<()> {
+        let (_state, url) = serve().await?;
+        let client = reqwest::Client::new();
+        let token_url = format!("{url}/token?token=foo");
+
+        let res = client
+            .get(&token_url)
+            .bearer_auth(ADMIN_TOKEN)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = client
+            .post(format!("{url}/token/register"))
+            .bearer_auth(ADMIN_TOKEN)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(r#"{"token": "foo"}"#)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = client
+            .get(&token_url)
+            .bearer_auth(ADMIN_TOKEN)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let status: serde_json::Value = serde_json::from_str(&res.text().await?)?;
+        assert_eq!(status["token"], "foo");
+        assert_eq!(status["record"]["provider"], "apns-production");
+
+        let res = client
+            .delete(&token_url)
+            .bearer_auth(ADMIN_TOKEN)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client
+            .delete(&token_url)
+            .bearer_auth(ADMIN_TOKEN)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = client
+            .get(&token_url)
+            .bearer_auth(ADMIN_TOKEN)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat() -> Result<()> {
+        let storage = MemoryStorage::default();
+        storage.insert(b"foo", &u64::to_be_bytes(10))?;
+        let config = Config {
+            rate_limits: std::collections::BTreeMap::from([("apns-production".to_string(), 1.0)]),
+            ..Config::default()
+        };
+        let (state, url) = serve_with(storage, config).await?;
+        let client = reqwest::Client::new();
+        let heartbeat = |token: &str| {
+            client
+                .post(format!("{url}/token/heartbeat"))
+                .bearer_auth(ADMIN_TOKEN)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(serde_json::json!({ "token": token }).to_string())
+                .send()
+        };
+
+        assert_eq!(
+            heartbeat("fcm-chat.delta:bar").await?.status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(heartbeat("bar").await?.status(), StatusCode::NOT_FOUND);
+
+        // Stored tokens cannot be notified before they are loaded.
+        assert_eq!(
+            heartbeat("foo").await?.status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        let schedule = state.schedule();
+        schedule.load()?;
+
+        // Tokens being notified by the dispatcher are not notified again.
+        assert_eq!(schedule.pop()?.unwrap(), (10, "foo".to_string()));
+        assert_eq!(heartbeat("foo").await?.status(), StatusCode::CONFLICT);
+        assert_eq!(schedule.compact(Duration::ZERO)?, 1);
+
+        // The rate limit is not waited for.
+        assert!(state
+            .rate_limiter()
+            .try_acquire("apns-production", ratelimit::Priority::Direct)
+            .is_ok());
+        let res = heartbeat("foo").await?;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().contains_key(header::RETRY_AFTER));
+
+        // Rejected heartbeats leave the token scheduled.
+        assert_eq!(schedule.pop()?.unwrap(), (10, "foo".to_string()));
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+//! Bounded in-memory cache with least recently used eviction.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Entry<V> {
+    value: V,
+
+    /// Time when the entry was inserted.
+    inserted: Instant,
+
+    /// Position of the entry in the usage order.
+    tick: u64,
+}
+
+/// Cache of at most `capacity` entries
+/// which expire `ttl` after insertion.
+///
+/// When the cache is full,
+/// the least recently used entry is evicted.
+#[derive(Debug)]
+pub(crate) struct LruCache<K, V> {
+    capacity: usize,
+
+    ttl: Duration,
+
+    entries: HashMap<K, Entry<V>>,
+
+    /// Keys ordered from the least recently used.
+    order: BTreeMap<u64, K>,
+
+    /// Tick of the latest use.
+    tick: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    /// Creates a new cache, `capacity` 0 disables caching.
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Returns the value of an unexpired entry
+    /// and marks it as recently used.
+    pub(crate) fn get(&mut self, key: &K, now: Instant) -> Option<V> {
+        let entry = self.entries.get_mut(key)?;
+        if now.saturating_duration_since(entry.inserted) >= self.ttl {
+            self.order.remove(&entry.tick);
+            self.entries.remove(key);
+            return None;
+        }
+        self.tick += 1;
+        self.order.remove(&entry.tick);
+        self.order.insert(self.tick, key.clone());
+        entry.tick = self.tick;
+        Some(entry.value.clone())
+    }
+
+    /// Changes the capacity and TTL,
+    /// evicting the least recently used entries that no longer fit.
+    pub(crate) fn resize(&mut self, capacity: usize, ttl: Duration) {
+        self.capacity = capacity;
+        self.ttl = ttl;
+        while self.entries.len() > capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: K, value: V, now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(entry) = self.entries.remove(&key) {
+            self.order.remove(&entry.tick);
+        }
+        while self.entries.len() >= self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                inserted: now,
+                tick: self.tick,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_cache() {
+        let now = Instant::now();
+        let mut cache = LruCache::new(2, Duration::from_secs(60));
+        cache.insert("a", 1, now);
+        cache.insert("b", 2, now);
+        assert_eq!(cache.get(&"a", now), Some(1));
+
+        // "b" is the least recently used entry.
+        cache.insert("c", 3, now);
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.get(&"b", now), None);
+        assert_eq!(cache.get(&"a", now), Some(1));
+        assert_eq!(cache.get(&"c", now), Some(3));
+
+        // Entries expire after the TTL.
+        let later = now + Duration::from_secs(60);
+        assert_eq!(cache.get(&"a", later), None);
+        assert_eq!(cache.entries.len(), 1);
+
+        // Shrinking evicts the least recently used entries.
+        cache.insert("b", 2, now);
+        cache.resize(1, Duration::from_secs(120));
+        assert_eq!(cache.get(&"c", now), None);
+        assert_eq!(cache.get(&"b", later), Some(2));
+
+        let mut disabled = LruCache::new(0, Duration::from_secs(60));
+        disabled.insert("a", 1, now);
+        assert_eq!(disabled.get(&"a", now), None);
+    }
+}
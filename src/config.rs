@@ -0,0 +1,415 @@
+//! Server configuration.
+//!
+//! Settings are read from an optional TOML file
+//! and can be overridden on the command line.
+//! Keys in the file are named like the command line options,
+//! e.g. `registration-ttl = "30days"`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{ensure, Context as _, Result};
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer};
+
+use crate::openpgp::TokenPolicy;
+use crate::ratelimit::{RateLimit, MIN_RATE};
+use crate::storage::Backend;
+
+/// Minimum length of the admin token,
+/// shorter tokens are easy to guess.
+const MIN_ADMIN_TOKEN_LEN: usize = 16;
+
+/// Secret value given directly,
+/// read from a file or from an environment variable.
+///
+/// In the configuration file secrets are written as
+/// `password = "secret"`, `password = { file = "/run/secrets/password" }`
+/// or `password = { env = "APNS_PASSWORD" }`.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Secret {
+    Value(String),
+    File { file: PathBuf },
+    Env { env: String },
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Value(_) => f.write_str("Value(..)"),
+            Self::File { file } => f.debug_struct("File").field("file", file).finish(),
+            Self::Env { env } => f.debug_struct("Env").field("env", env).finish(),
+        }
+    }
+}
+
+impl Secret {
+    /// Returns the secret value.
+    ///
+    /// Trailing newline is removed from secrets read from files.
+    pub fn read(&self) -> Result<String> {
+        match self {
+            Self::Value(value) => Ok(value.clone()),
+            Self::File { file } => {
+                let value = std::fs::read_to_string(file)
+                    .with_context(|| format!("Failed to read secret from {}", file.display()))?;
+                Ok(value.trim_end_matches(['\r', '\n']).to_string())
+            }
+            Self::Env { env } => {
+                std::env::var(env).with_context(|| format!("Failed to read secret from ${env}"))
+            }
+        }
+    }
+}
+
+/// Configuration of the server.
+///
+/// Credentials, rate limits, the topic and the log level
+/// are reloaded on SIGHUP and when credential files change,
+/// other settings require a restart.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Path to the certificate file PKS12.
+    pub certificate_file: Option<PathBuf>,
+
+    /// Password for the certificate file.
+    pub password: Option<Secret>,
+
+    /// The topic for the notification.
+    pub topic: Option<String>,
+
+    /// The host on which to start the server.
+    pub host: String,
+
+    /// The port on which to start the server.
+    pub port: u16,
+
+    /// The host and port on which to start the metrics server.
+    pub metrics: Option<String>,
+
+    /// The host and port on which to start the admin API server.
+    pub admin: Option<String>,
+
+    /// Token authenticating requests to the admin API.
+    pub admin_token: Option<Secret>,
+
+    /// The path to the database file.
+    pub db: PathBuf,
+
+    /// The database backend.
+    #[serde(deserialize_with = "from_str")]
+    pub db_backend: Backend,
+
+    /// Path to the file with the key for encrypting the database.
+    pub db_encryption_key_file: Option<PathBuf>,
+
+    /// Heartbeat notification interval.
+    #[serde(deserialize_with = "duration")]
+    pub interval: Duration,
+
+    /// Time after which heartbeat tokens that were not registered again are removed.
+    #[serde(deserialize_with = "duration")]
+    pub registration_ttl: Duration,
+
+    /// Maximum number of heartbeat notifications sent concurrently.
+    pub workers: usize,
+
+    /// Maximum number of heartbeat notifications sent per second.
+    pub max_send_rate: Option<f64>,
+
+    /// Maximum number of notifications per second sent to each provider.
+    pub rate_limits: BTreeMap<String, f64>,
+
+    /// Time to wait for heartbeat notifications being sent on shutdown.
+    #[serde(deserialize_with = "duration")]
+    pub shutdown_timeout: Duration,
+
+    /// Number of consecutive failed heartbeat notifications
+    /// after which a token may be removed.
+    pub max_failures: u32,
+
+    /// Minimum time a token must be failing before it is removed.
+    #[serde(deserialize_with = "duration")]
+    pub failure_period: Duration,
+
+    /// Path to FCM private key.
+    pub fcm_key_path: Option<PathBuf>,
+
+    /// Path to the OpenPGP private keyring.
+    pub openpgp_keyring_path: Option<PathBuf>,
+
+    /// Passphrases of protected OpenPGP keys by key fingerprint.
+    pub openpgp_passphrases: BTreeMap<String, Secret>,
+
+    /// Maximum number of decrypted tokens kept in memory,
+    /// 0 disables the cache.
+    pub openpgp_cache_size: usize,
+
+    /// Time for which decrypted tokens are cached.
+    #[serde(deserialize_with = "duration")]
+    pub openpgp_cache_ttl: Duration,
+
+    /// Reject encrypted tokens without an envelope.
+    pub openpgp_reject_legacy_tokens: bool,
+
+    /// Accepted audiences of encrypted tokens, e.g. `chat.example.org`.
+    pub openpgp_audiences: Vec<String>,
+
+    /// Maximum time since an encrypted token was issued.
+    #[serde(deserialize_with = "option_duration")]
+    pub openpgp_token_max_age: Option<Duration>,
+
+    /// Fingerprints of OpenPGP keys which clients should stop using.
+    pub openpgp_deprecated_keys: Vec<String>,
+
+    /// Fingerprints of OpenPGP keys whose tokens are rejected.
+    pub openpgp_retired_keys: Vec<String>,
+
+    /// Maximum level of log messages.
+    #[serde(deserialize_with = "from_str")]
+    pub log_level: LevelFilter,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            certificate_file: None,
+            password: None,
+            topic: None,
+            host: "127.0.0.1".to_string(),
+            port: 9000,
+            metrics: None,
+            admin: None,
+            admin_token: None,
+            db: PathBuf::from("notifiers.db"),
+            db_backend: Backend::Sled,
+            db_encryption_key_file: None,
+            interval: Duration::from_secs(20 * 60),
+            registration_ttl: Duration::from_secs(90 * 24 * 60 * 60),
+            workers: 50,
+            max_send_rate: None,
+            rate_limits: BTreeMap::new(),
+            shutdown_timeout: Duration::from_secs(30),
+            max_failures: 10,
+            failure_period: Duration::from_secs(3 * 24 * 60 * 60),
+            fcm_key_path: None,
+            openpgp_keyring_path: None,
+            openpgp_passphrases: BTreeMap::new(),
+            openpgp_cache_size: 10_000,
+            openpgp_cache_ttl: Duration::from_secs(60 * 60),
+            openpgp_reject_legacy_tokens: false,
+            openpgp_audiences: Vec::new(),
+            openpgp_token_max_age: None,
+            openpgp_deprecated_keys: Vec::new(),
+            openpgp_retired_keys: Vec::new(),
+            log_level: LevelFilter::Info,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the configuration from a TOML file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let config = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read configuration {}", path.display()))?;
+        toml::from_str(&config)
+            .with_context(|| format!("Failed to parse configuration {}", path.display()))
+    }
+
+    /// Checks that the settings are valid.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.workers > 0, "workers must be at least 1");
+        if let Some(max_send_rate) = self.max_send_rate {
+            ensure!(
+                max_send_rate >= MIN_RATE && max_send_rate.is_finite(),
+                "max-send-rate must be at least {MIN_RATE}"
+            );
+        }
+        self.rate_limits()?;
+        let admin_token = self.admin_token()?;
+        if self.admin.is_some() {
+            ensure!(admin_token.is_some(), "admin-token is required with admin");
+        }
+        Ok(())
+    }
+
+    /// Returns the paths of the files containing credentials.
+    pub fn credential_files(&self) -> Vec<PathBuf> {
+        let secret_files = self
+            .password
+            .iter()
+            .chain(self.admin_token.iter())
+            .chain(self.openpgp_passphrases.values())
+            .filter_map(|secret| match secret {
+                Secret::File { file } => Some(file),
+                _ => None,
+            });
+        [
+            self.certificate_file.as_ref(),
+            self.fcm_key_path.as_ref(),
+            self.openpgp_keyring_path.as_ref(),
+        ]
+        .iter()
+        .flatten()
+        .copied()
+        .chain(secret_files)
+        .map(|file| file.to_path_buf())
+        .collect()
+    }
+
+    /// Returns the requirements for encrypted tokens.
+    pub fn openpgp_token_policy(&self) -> TokenPolicy {
+        TokenPolicy {
+            allow_legacy: !self.openpgp_reject_legacy_tokens,
+            audiences: self.openpgp_audiences.clone(),
+            max_age: self.openpgp_token_max_age,
+        }
+    }
+
+    /// Reads the token authenticating requests to the admin API.
+    ///
+    /// Fails if the token is shorter than [`MIN_ADMIN_TOKEN_LEN`].
+    pub fn admin_token(&self) -> Result<Option<String>> {
+        let Some(ref admin_token) = self.admin_token else {
+            return Ok(None);
+        };
+        let admin_token = admin_token.read().context("Failed to read admin-token")?;
+        ensure!(
+            admin_token.chars().count() >= MIN_ADMIN_TOKEN_LEN,
+            "admin-token must be at least {MIN_ADMIN_TOKEN_LEN} characters long"
+        );
+        Ok(Some(admin_token))
+    }
+
+    /// Reads the passphrases of the OpenPGP keys.
+    pub fn openpgp_passphrases(&self) -> Result<BTreeMap<String, String>> {
+        self.openpgp_passphrases
+            .iter()
+            .map(|(fingerprint, passphrase)| {
+                let passphrase = passphrase.read().with_context(|| {
+                    format!("Failed to read passphrase of OpenPGP key {fingerprint}")
+                })?;
+                Ok((fingerprint.clone(), passphrase))
+            })
+            .collect()
+    }
+
+    /// Returns the configured rate limits.
+    pub fn rate_limits(&self) -> Result<Vec<RateLimit>> {
+        self.rate_limits
+            .iter()
+            .map(|(provider, &rate)| RateLimit::new(provider, rate))
+            .collect()
+    }
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    humantime::parse_duration(&s).map_err(serde::de::Error::custom)
+}
+
+fn option_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    duration(deserializer).map(Some)
+}
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_config() -> Result<()> {
+        let dir = tempdir()?;
+        let password_file = dir.path().join("password");
+        std::fs::write(&password_file, "secret\n")?;
+        let config_file = dir.path().join("notifiers.toml");
+        std::fs::write(
+            &config_file,
+            format!(
+                r#"
+port = 9100
+db-backend = "sqlite"
+interval = "10m"
+openpgp-token-max-age = "1day"
+password = {{ file = "{}" }}
+log-level = "debug"
+
+[rate-limits]
+apns-production = 100
+
+[openpgp-passphrases]
+"AB CD" = {{ file = "{}" }}
+"#,
+                password_file.display(),
+                password_file.display()
+            ),
+        )?;
+
+        let config = Config::from_file(&config_file)?;
+        config.validate()?;
+        assert_eq!(config.port, 9100);
+        assert_eq!(config.host, "127.0.0.1");
+        assert_eq!(config.db_backend, Backend::Sqlite);
+        assert_eq!(config.interval, Duration::from_secs(600));
+        assert_eq!(config.log_level, LevelFilter::Debug);
+        assert_eq!(
+            config.openpgp_token_max_age,
+            Some(Duration::from_secs(24 * 60 * 60))
+        );
+        assert_eq!(config.password.as_ref().unwrap().read()?, "secret");
+        assert_eq!(
+            config.rate_limits()?,
+            vec![RateLimit::new("apns-production", 100.0)?]
+        );
+        assert_eq!(
+            config.openpgp_passphrases()?,
+            BTreeMap::from([("AB CD".to_string(), "secret".to_string())])
+        );
+        assert_eq!(
+            config.credential_files(),
+            vec![password_file.clone(), password_file]
+        );
+
+        std::fs::write(&config_file, "unknown = 1")?;
+        assert!(Config::from_file(&config_file).is_err());
+
+        let config = Config {
+            max_send_rate: Some(1e-300),
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        // Empty and short admin tokens are rejected.
+        let mut config = Config {
+            admin: Some("127.0.0.1:9002".to_string()),
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+        for admin_token in ["", "short"] {
+            config.admin_token = Some(Secret::Value(admin_token.to_string()));
+            assert!(config.validate().is_err());
+        }
+        config.admin_token = Some(Secret::Value("0123456789abcdef".to_string()));
+        config.validate()?;
+        assert_eq!(config.admin_token()?.unwrap(), "0123456789abcdef");
+        Ok(())
+    }
+}
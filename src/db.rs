@@ -0,0 +1,252 @@
+//! Inspection, export and import of the tokens stored in the database.
+//!
+//! Tokens are exported as JSON lines,
+//! one object per token with the fields of its [`TokenRecord`].
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{BufRead, Write};
+
+use anyhow::{Context as _, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+use crate::schedule::{Schedule, TokenRecord};
+use crate::server::NotificationToken;
+
+/// Upper bounds of the buckets of the time since the last successful notification.
+const AGE_BUCKETS: [(&str, u64); 4] = [
+    ("1h", 60 * 60),
+    ("1d", 24 * 60 * 60),
+    ("7d", 7 * 24 * 60 * 60),
+    ("30d", 30 * 24 * 60 * 60),
+];
+
+/// Token exported as a JSON line.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedToken {
+    /// The token, `None` if exported hashed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+
+    /// Hex SHA-256 hash of the token if exported hashed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_sha256: Option<String>,
+
+    #[serde(flatten)]
+    pub record: TokenRecord,
+}
+
+/// Statistics of the stored tokens.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub tokens: usize,
+
+    /// Number of tokens by provider.
+    pub providers: BTreeMap<String, usize>,
+
+    /// Number of tokens whose latest heartbeat notification failed.
+    pub failing: usize,
+
+    /// Number of tokens by time since the last successful notification,
+    /// labeled by the upper bound of the bucket, `older` or `never`.
+    pub last_success: BTreeMap<String, usize>,
+
+    /// Number of stored records which cannot be read.
+    pub invalid: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Tokens: {}", self.tokens)?;
+        writeln!(f, "Failing: {}", self.failing)?;
+        writeln!(f, "Invalid: {}", self.invalid)?;
+        writeln!(f, "Providers:")?;
+        for (provider, count) in &self.providers {
+            writeln!(f, "  {provider}: {count}")?;
+        }
+        writeln!(f, "Last successful notification:")?;
+        let labels = AGE_BUCKETS.iter().map(|(label, _)| *label);
+        for label in labels.chain(["older", "never"]) {
+            let count = self.last_success.get(label).copied().unwrap_or_default();
+            writeln!(f, "  {label}: {count}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Computes statistics of the stored tokens at Unix time `now`.
+///
+/// Records which cannot be read are counted as invalid.
+pub fn stats(schedule: &Schedule, now: u64) -> Result<Stats> {
+    let mut stats = Stats::default();
+    for entry in schedule.records() {
+        let (token, record) = entry?;
+        let record = match record {
+            Ok(record) => record,
+            Err(err) => {
+                warn!("Skipping token {token}: {err:#}");
+                stats.invalid += 1;
+                continue;
+            }
+        };
+        stats.tokens += 1;
+        let provider = match record.provider {
+            Some(ref provider) => provider.as_str(),
+            None => token
+                .parse::<NotificationToken>()
+                .map_or("unknown", |token| token.provider()),
+        };
+        *stats.providers.entry(provider.to_string()).or_default() += 1;
+        if record.failures > 0 {
+            stats.failing += 1;
+        }
+        let label = match record.last_success {
+            Some(last_success) => {
+                let age = now.saturating_sub(last_success);
+                AGE_BUCKETS
+                    .iter()
+                    .find(|(_, max_age)| age <= *max_age)
+                    .map_or("older", |(label, _)| label)
+            }
+            None => "never",
+        };
+        *stats.last_success.entry(label.to_string()).or_default() += 1;
+    }
+    Ok(stats)
+}
+
+/// Writes all stored tokens as JSON lines.
+///
+/// If `hash_tokens` is set, tokens are replaced by their hashes,
+/// such exports cannot be imported.
+/// Records which cannot be read are logged and skipped.
+///
+/// Returns the number of exported tokens and the number of skipped records.
+pub fn export(
+    schedule: &Schedule,
+    mut writer: impl Write,
+    hash_tokens: bool,
+) -> Result<(usize, usize)> {
+    let mut exported = 0;
+    let mut skipped = 0;
+    for entry in schedule.records() {
+        let (token, record) = entry?;
+        let record = match record {
+            Ok(record) => record,
+            Err(err) => {
+                warn!("Skipping token {token}: {err:#}");
+                skipped += 1;
+                continue;
+            }
+        };
+        let line = if hash_tokens {
+            let hash = Sha256::digest(token.as_bytes());
+            ExportedToken {
+                token: None,
+                token_sha256: Some(hash.iter().map(|byte| format!("{byte:02x}")).collect()),
+                record,
+            }
+        } else {
+            ExportedToken {
+                token: Some(token),
+                token_sha256: None,
+                record,
+            }
+        };
+        serde_json::to_writer(&mut writer, &line)?;
+        writer.write_all(b"\n")?;
+        exported += 1;
+    }
+    writer.flush()?;
+    Ok((exported, skipped))
+}
+
+/// Reads tokens exported by [`export`]
+/// and stores them, replacing the records of existing tokens.
+///
+/// Returns the number of imported tokens.
+pub async fn import(schedule: &Schedule, reader: impl BufRead) -> Result<usize> {
+    let mut imported = 0;
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let exported: ExportedToken = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid token on line {}", number + 1))?;
+        let token = exported.token.with_context(|| {
+            format!(
+                "No token on line {}, hashed tokens cannot be imported",
+                number + 1
+            )
+        })?;
+        schedule.import_record(&token, exported.record)?;
+        imported += 1;
+    }
+    schedule.flush().await?;
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::{MemoryStorage, Storage as _};
+
+    #[tokio::test]
+    async fn test_export_import() -> Result<()> {
+        let schedule = Schedule::with_storage(Box::<MemoryStorage>::default());
+        schedule.register_token("foo", 1000)?;
+        schedule.register_token("fcm-chat.delta:bar", 1000)?;
+        schedule.record_success("foo")?;
+
+        let mut exported = Vec::new();
+        assert_eq!(export(&schedule, &mut exported, false)?, (2, 0));
+
+        let imported = Schedule::with_storage(Box::<MemoryStorage>::default());
+        assert_eq!(import(&imported, exported.as_slice()).await?, 2);
+        assert_eq!(imported.get("foo")?, schedule.get("foo")?);
+        assert_eq!(
+            imported.get("fcm-chat.delta:bar")?,
+            schedule.get("fcm-chat.delta:bar")?
+        );
+
+        let now = schedule.get("foo")?.unwrap().last_success.unwrap();
+        let stats = stats(&imported, now)?;
+        assert_eq!(stats.tokens, 2);
+        assert_eq!(stats.providers.get("apns-production"), Some(&1));
+        assert_eq!(stats.providers.get("fcm"), Some(&1));
+        assert_eq!(stats.last_success.get("1h"), Some(&1));
+        assert_eq!(stats.last_success.get("never"), Some(&1));
+
+        // Hashed exports do not contain tokens and cannot be imported.
+        let mut hashed = Vec::new();
+        export(&schedule, &mut hashed, true)?;
+        let hashed = String::from_utf8(hashed)?;
+        assert!(!hashed.contains("foo"));
+        assert!(import(&imported, hashed.as_bytes()).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_records() -> Result<()> {
+        let storage = MemoryStorage::default();
+        storage.insert(b"bar", &[0xff, b'{'])?;
+        storage.insert(&[0xff], b"")?;
+        let schedule = Schedule::with_storage(Box::new(storage));
+        schedule.register_token("foo", 1000)?;
+
+        let stats = stats(&schedule, 1000)?;
+        assert_eq!(stats.tokens, 1);
+        assert_eq!(stats.invalid, 2);
+
+        let mut exported = Vec::new();
+        assert_eq!(export(&schedule, &mut exported, false)?, (1, 2));
+        let exported = String::from_utf8(exported)?;
+        assert!(exported.contains("foo"));
+        assert!(!exported.contains("bar"));
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+//! Encryption of the schedule storage at rest.
+//!
+//! Tokens are never stored in plaintext.
+//! Entries are stored under a keyed hash of the token,
+//! and values are encrypted together with the token
+//! so the token can be recovered when iterating over the storage.
+
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context as _, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::storage::{Entries, Flush, Storage};
+
+/// Minimum size of the key material in the key file.
+const MIN_KEY_SIZE: usize = 32;
+
+/// Size of the XChaCha20-Poly1305 nonce prepended to encrypted values.
+const NONCE_SIZE: usize = 24;
+
+/// Keys for encrypting the storage,
+/// derived from the key material in the key file.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    /// Key for encrypting values.
+    cipher_key: [u8; 32],
+
+    /// Key for hashing tokens into storage keys.
+    index_key: [u8; 32],
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey").finish_non_exhaustive()
+    }
+}
+
+impl EncryptionKey {
+    /// Derives the keys from secret key material,
+    /// e.g. 32 random bytes.
+    pub fn new(material: &[u8]) -> Result<Self> {
+        ensure!(
+            material.len() >= MIN_KEY_SIZE,
+            "Encryption key must be at least {MIN_KEY_SIZE} bytes long"
+        );
+        let hkdf = Hkdf::<Sha256>::new(None, material);
+        let mut cipher_key = [0; 32];
+        hkdf.expand(b"notifiers storage encryption", &mut cipher_key)
+            .expect("valid output length");
+        let mut index_key = [0; 32];
+        hkdf.expand(b"notifiers storage index", &mut index_key)
+            .expect("valid output length");
+        Ok(Self {
+            cipher_key,
+            index_key,
+        })
+    }
+
+    /// Reads the key material from a file.
+    ///
+    /// The file can be created with
+    /// `openssl rand -out <file> 32`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let material = std::fs::read(path)
+            .with_context(|| format!("Failed to read encryption key {}", path.display()))?;
+        Self::new(&material)
+    }
+}
+
+/// Storage encrypting all tokens and values
+/// before passing them to the underlying storage.
+pub struct EncryptedStorage {
+    inner: Box<dyn Storage>,
+
+    cipher: XChaCha20Poly1305,
+
+    index_key: [u8; 32],
+}
+
+impl fmt::Debug for EncryptedStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedStorage")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Box<dyn Storage>, key: &EncryptionKey) -> Self {
+        Self {
+            inner,
+            cipher: XChaCha20Poly1305::new(&key.cipher_key.into()),
+            index_key: key.index_key,
+        }
+    }
+
+    /// Returns the key under which the entry for `key` is stored.
+    fn index(&self, key: &[u8]) -> Vec<u8> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.index_key)
+            .expect("HMAC accepts any key size");
+        mac.update(key);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn encrypt(&self, index: &[u8], key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        ensure!(key.len() <= u32::MAX as usize, "Key is too long");
+        let key_len = key.len() as u32;
+        let mut plaintext = Vec::with_capacity(4 + key.len() + value.len());
+        plaintext.extend_from_slice(&key_len.to_be_bytes());
+        plaintext.extend_from_slice(key);
+        plaintext.extend_from_slice(value);
+
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        // The storage key is authenticated
+        // so entries cannot be swapped in the database.
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plaintext,
+                    aad: index,
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt entry"))?;
+
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend_from_slice(&ciphertext);
+        Ok(encrypted)
+    }
+
+    /// Decrypts a stored entry and returns the original key and value.
+    fn decrypt(&self, index: &[u8], encrypted: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        ensure!(
+            encrypted.len() >= NONCE_SIZE,
+            "Encrypted entry is too short"
+        );
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_SIZE);
+        let Ok(mut plaintext) = self.cipher.decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: index,
+            },
+        ) else {
+            bail!("Failed to decrypt entry, the database is not encrypted or the key is wrong");
+        };
+
+        ensure!(plaintext.len() >= 4, "Decrypted entry is too short");
+        let value = plaintext.split_off(4);
+        let mut buf: [u8; 4] = [0; 4];
+        buf.copy_from_slice(&plaintext);
+        let key_len = u32::from_be_bytes(buf) as usize;
+        ensure!(value.len() >= key_len, "Decrypted entry is too short");
+        let (key, value) = value.split_at(key_len);
+        Ok((key.to_vec(), value.to_vec()))
+    }
+}
+
+impl Storage for EncryptedStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let index = self.index(key);
+        let Some(encrypted) = self.inner.get(&index)? else {
+            return Ok(None);
+        };
+        let (stored_key, value) = self.decrypt(&index, &encrypted)?;
+        ensure!(stored_key == key, "Stored key does not match");
+        Ok(Some(value))
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let index = self.index(key);
+        let encrypted = self.encrypt(&index, key, value)?;
+        self.inner.insert(&index, &encrypted)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        self.inner.remove(&self.index(key))
+    }
+
+    fn iter(&self) -> Entries<'_> {
+        Box::new(self.inner.iter().map(move |entry| {
+            let (index, encrypted) = entry?;
+            self.decrypt(&index, &encrypted)
+        }))
+    }
+
+    fn flush(&self) -> Flush<'_> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_encrypted_storage() -> Result<()> {
+        let key = EncryptionKey::new(&[1; 32])?;
+        let storage = EncryptedStorage::new(Box::<MemoryStorage>::default(), &key);
+
+        storage.insert(b"foo", b"value")?;
+        storage.insert(b"bar", b"")?;
+        assert_eq!(storage.get(b"foo")?, Some(b"value".to_vec()));
+        assert_eq!(storage.get(b"bar")?, Some(b"".to_vec()));
+        assert_eq!(storage.get(b"baz")?, None);
+
+        let mut entries = storage.iter().collect::<Result<Vec<_>>>()?;
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![
+                (b"bar".to_vec(), b"".to_vec()),
+                (b"foo".to_vec(), b"value".to_vec())
+            ]
+        );
+
+        // Underlying storage does not contain tokens or values.
+        for entry in storage.inner.iter() {
+            let (index, encrypted) = entry?;
+            assert_eq!(index.len(), 32);
+            assert!(!encrypted.windows(3).any(|w| w == b"foo" || w == b"val"));
+        }
+
+        storage.remove(b"foo")?;
+        assert_eq!(storage.get(b"foo")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrong_key() -> Result<()> {
+        let inner = Box::<MemoryStorage>::default();
+        inner.insert(b"foo", b"plaintext")?;
+        let key = EncryptionKey::new(&[1; 32])?;
+        let storage = EncryptedStorage::new(inner, &key);
+        assert!(storage.iter().next().unwrap().is_err());
+
+        assert!(EncryptionKey::new(&[1; 16]).is_err());
+        Ok(())
+    }
+}
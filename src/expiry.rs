@@ -0,0 +1,128 @@
+//! Monitoring of credential expiry.
+//!
+//! Expiry times of the APNS certificate and the OpenPGP keys
+//! are exported as metrics and logged as warnings
+//! when the expiry approaches.
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context as _, Result};
+use log::*;
+use openssl::asn1::Asn1Time;
+use openssl::pkcs12::Pkcs12;
+
+use crate::state::State;
+
+/// Time before the expiry when warnings are logged.
+const WARN_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Returns the expiry time of the certificate in a PKCS#12 archive
+/// as a Unix timestamp.
+pub(crate) fn certificate_expiry(pkcs12: &[u8], password: &str) -> Result<i64> {
+    let parsed = Pkcs12::from_der(pkcs12)?
+        .parse2(password)
+        .context("Failed to decrypt the certificate")?;
+    let cert = parsed.cert.context("No certificate in the archive")?;
+    let diff = Asn1Time::from_unix(0)?.diff(cert.not_after())?;
+    Ok(i64::from(diff.days) * 24 * 60 * 60 + i64::from(diff.secs))
+}
+
+/// Periodically updates expiry metrics
+/// and warns about credentials expiring soon.
+pub async fn monitor(state: State) {
+    loop {
+        check(&state);
+        tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+    }
+}
+
+/// Updates expiry metrics and warns about credentials expiring soon.
+///
+/// Called after reloading the configuration
+/// so the metrics reflect renewed or removed credentials.
+pub(crate) fn check(state: &State) {
+    let metrics = state.metrics();
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+
+    match state.apns_certificate_expiry() {
+        Some(expiry) => {
+            metrics
+                .apns_certificate_expiry_timestamp_seconds
+                .set(expiry);
+            warn_if_expiring("APNS certificate", expiry, now);
+        }
+        None => {
+            metrics.apns_certificate_expiry_timestamp_seconds.set(0);
+        }
+    }
+
+    metrics.openpgp_key_expiry_timestamp_seconds.clear();
+    if let Some(decryptor) = state.openpgp_decryptor() {
+        for (fingerprint, expiry) in decryptor.key_expiry() {
+            let Some(expiry) = expiry else {
+                continue;
+            };
+            metrics
+                .openpgp_key_expiry_timestamp_seconds
+                .get_or_create(&vec![("fingerprint".to_string(), fingerprint.clone())])
+                .set(expiry);
+            warn_if_expiring(&format!("OpenPGP key {fingerprint}"), expiry, now);
+        }
+    }
+}
+
+fn warn_if_expiring(what: &str, expiry: i64, now: i64) {
+    let remaining = expiry - now;
+    if remaining <= 0 {
+        error!("{what} has expired.");
+    } else if remaining <= WARN_BEFORE.as_secs() as i64 {
+        warn!(
+            "{what} expires in {}.",
+            humantime::format_duration(Duration::from_secs(remaining as u64))
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::{X509Name, X509};
+
+    #[test]
+    fn test_certificate_expiry() -> Result<()> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let pkey = PKey::from_ec_key(EcKey::generate(&group)?)?;
+        let mut name = X509Name::builder()?;
+        name.append_entry_by_text("CN", "notifiers")?;
+        let name = name.build();
+
+        let expiry = 2_000_000_000;
+        let mut cert = X509::builder()?;
+        cert.set_version(2)?;
+        cert.set_subject_name(&name)?;
+        cert.set_issuer_name(&name)?;
+        cert.set_pubkey(&pkey)?;
+        cert.set_not_before(Asn1Time::from_unix(1_700_000_000)?.as_ref())?;
+        cert.set_not_after(Asn1Time::from_unix(expiry)?.as_ref())?;
+        cert.sign(&pkey, MessageDigest::sha256())?;
+        let cert = cert.build();
+
+        let pkcs12 = Pkcs12::builder()
+            .name("notifiers")
+            .pkey(&pkey)
+            .cert(&cert)
+            .build2("password")?
+            .to_der()?;
+        assert_eq!(certificate_expiry(&pkcs12, "password")?, expiry);
+        assert!(certificate_expiry(&pkcs12, "wrong").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+//! In-memory index of scheduled tokens.
+//!
+//! Tokens are distributed over independently locked shards
+//! so concurrent registrations and notifications
+//! do not contend on a single lock.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::BuildHasher;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Number of shards of the index.
+const SHARDS: usize = 64;
+
+/// Tokens ordered by timestamp.
+///
+/// Each token is stored at most once,
+/// its previous entry is replaced when it is inserted again.
+/// Popped tokens are tracked as in flight until they are inserted again or removed.
+#[derive(Debug)]
+pub struct Index {
+    shards: Vec<Mutex<Shard>>,
+
+    hasher: RandomState,
+}
+
+/// Part of the [`Index`] containing tokens with the same hash.
+#[derive(Debug, Default)]
+pub struct Shard {
+    /// Tokens ordered by timestamp.
+    queue: BTreeSet<(u64, Arc<str>)>,
+
+    /// Timestamps of the tokens in the queue.
+    timestamps: HashMap<Arc<str>, u64>,
+
+    /// Tokens popped from the queue and the time they were popped.
+    in_flight: HashMap<Arc<str>, Instant>,
+}
+
+impl Index {
+    pub fn new() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Locks the shard containing the token.
+    ///
+    /// Holding the lock serializes updates of the token.
+    pub fn shard(&self, token: &str) -> MutexGuard<'_, Shard> {
+        let shard = self.hasher.hash_one(token) as usize % self.shards.len();
+        self.shards[shard].lock().unwrap()
+    }
+
+    /// Returns the earliest timestamp in the index.
+    pub fn first(&self) -> Option<u64> {
+        self.shards
+            .iter()
+            .filter_map(|shard| shard.lock().unwrap().first())
+            .min()
+    }
+
+    /// Removes the token with the earliest timestamp from the index.
+    pub fn pop(&self) -> Option<(u64, Arc<str>)> {
+        self.pop_until(u64::MAX, |_token| Ok::<_, ()>(()))
+            .unwrap_or_default()
+    }
+
+    /// Removes the token with the earliest timestamp from the index
+    /// if the timestamp is not later than `max_timestamp`
+    /// and `admit` accepts the token.
+    ///
+    /// `admit` is called while holding the lock of the token shard,
+    /// if it returns an error the token is left in the index.
+    pub fn pop_until<E>(
+        &self,
+        max_timestamp: u64,
+        mut admit: impl FnMut(&str) -> Result<(), E>,
+    ) -> Result<Option<(u64, Arc<str>)>, E> {
+        loop {
+            let first = self
+                .shards
+                .iter()
+                .filter_map(|shard| Some((shard.lock().unwrap().first()?, shard)))
+                .min_by_key(|(timestamp, _shard)| *timestamp);
+            let Some((timestamp, shard)) = first else {
+                return Ok(None);
+            };
+            if timestamp > max_timestamp {
+                return Ok(None);
+            }
+
+            let mut shard = shard.lock().unwrap();
+            let Some((first_timestamp, token)) = shard.queue.first() else {
+                continue;
+            };
+            if *first_timestamp != timestamp {
+                // Shard was modified concurrently, retry.
+                continue;
+            }
+            admit(token)?;
+            return Ok(shard.pop());
+        }
+    }
+
+    /// Returns the number of tokens in the index,
+    /// including tokens in flight.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let shard = shard.lock().unwrap();
+                shard.timestamps.len() + shard.in_flight.len()
+            })
+            .sum()
+    }
+
+    /// Returns tokens which are in flight for longer than `timeout`.
+    pub fn stale(&self, timeout: Duration) -> Vec<Arc<str>> {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.lock().unwrap().stale(timeout))
+            .collect()
+    }
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shard {
+    /// Inserts the token or updates its timestamp.
+    pub fn insert(&mut self, token: &str, timestamp: u64) {
+        let token: Arc<str> =
+            if let Some((token, old_timestamp)) = self.timestamps.remove_entry(token) {
+                self.queue.remove(&(old_timestamp, Arc::clone(&token)));
+                token
+            } else if let Some((token, _popped_at)) = self.in_flight.remove_entry(token) {
+                token
+            } else {
+                Arc::from(token)
+            };
+        self.queue.insert((timestamp, Arc::clone(&token)));
+        self.timestamps.insert(token, timestamp);
+    }
+
+    /// Removes the token.
+    pub fn remove(&mut self, token: &str) {
+        if let Some((token, timestamp)) = self.timestamps.remove_entry(token) {
+            self.queue.remove(&(timestamp, token));
+        }
+        self.in_flight.remove(token);
+    }
+
+    /// Returns true if the token is waiting in the queue,
+    /// i.e. it is neither missing nor in flight.
+    pub fn is_queued(&self, token: &str) -> bool {
+        self.timestamps.contains_key(token)
+    }
+
+    /// Removes the token from the queue and tracks it as in flight.
+    ///
+    /// Returns the timestamp of the token
+    /// or `None` if the token is not queued.
+    pub fn take(&mut self, token: &str) -> Option<u64> {
+        let (token, timestamp) = self.timestamps.remove_entry(token)?;
+        self.queue.remove(&(timestamp, Arc::clone(&token)));
+        self.in_flight.insert(token, Instant::now());
+        Some(timestamp)
+    }
+
+    /// Returns true if the token is in flight for longer than `timeout`.
+    pub fn is_stale(&self, token: &str, timeout: Duration) -> bool {
+        self.in_flight
+            .get(token)
+            .is_some_and(|popped_at| popped_at.elapsed() > timeout)
+    }
+
+    fn stale(&self, timeout: Duration) -> Vec<Arc<str>> {
+        self.in_flight
+            .iter()
+            .filter(|(_token, popped_at)| popped_at.elapsed() > timeout)
+            .map(|(token, _popped_at)| Arc::clone(token))
+            .collect()
+    }
+
+    fn first(&self) -> Option<u64> {
+        self.queue.first().map(|(timestamp, _token)| *timestamp)
+    }
+
+    fn pop(&mut self) -> Option<(u64, Arc<str>)> {
+        let (timestamp, token) = self.queue.pop_first()?;
+        self.timestamps.remove(&token);
+        self.in_flight.insert(Arc::clone(&token), Instant::now());
+        Some((timestamp, token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index() {
+        let index = Index::new();
+        assert_eq!(index.len(), 0);
+        assert_eq!(index.pop(), None);
+
+        index.shard("foo").insert("foo", 30);
+        index.shard("bar").insert("bar", 10);
+        index.shard("baz").insert("baz", 20);
+        index.shard("bar").insert("bar", 40);
+        assert_eq!(index.len(), 3);
+
+        index.shard("baz").remove("baz");
+        index.shard("baz").remove("baz");
+        assert_eq!(index.len(), 2);
+
+        assert_eq!(index.first(), Some(30));
+        assert_eq!(index.pop_until(20, |_| Ok::<_, ()>(())), Ok(None));
+
+        // Tokens which are not admitted stay in the index.
+        assert_eq!(index.pop_until(30, |_| Err(())), Err(()));
+        assert_eq!(index.first(), Some(30));
+        assert_eq!(
+            index.pop_until(30, |_| Ok::<_, ()>(())),
+            Ok(Some((30, Arc::from("foo"))))
+        );
+        assert_eq!(index.pop(), Some((40, Arc::from("bar"))));
+        assert_eq!(index.pop(), None);
+
+        // Popped tokens are in flight.
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.stale(Duration::ZERO).len(), 2);
+        assert!(index.stale(Duration::from_secs(60)).is_empty());
+
+        index.shard("foo").insert("foo", 50);
+        index.shard("bar").remove("bar");
+        assert_eq!(index.len(), 1);
+        assert!(index.stale(Duration::ZERO).is_empty());
+        assert_eq!(index.pop(), Some((50, Arc::from("foo"))));
+    }
+}
@@ -1,6 +1,16 @@
+pub mod admin;
+mod cache;
+pub mod config;
+pub mod db;
+pub mod encryption;
+pub mod expiry;
+mod index;
 pub mod metrics;
 pub mod notifier;
-mod openpgp;
+pub mod openpgp;
+pub mod ratelimit;
 pub mod schedule;
+pub mod send;
 pub mod server;
 pub mod state;
+pub mod storage;
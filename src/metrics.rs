@@ -12,12 +12,14 @@ use axum::response::IntoResponse;
 use axum::routing::get;
 use prometheus_client::encoding::text::encode;
 use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
 use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
 use prometheus_client::registry::Registry;
 
 use crate::state::State;
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct Metrics {
     pub registry: Registry,
 
@@ -39,8 +41,41 @@ pub struct Metrics {
     /// Number of tokens registered for heartbeat notifications.
     pub heartbeat_tokens: Gauge<i64, AtomicI64>,
 
+    /// Number of heartbeat tokens removed because their registration expired.
+    pub heartbeat_expired_tokens_total: Counter,
+
+    /// Number of stale schedule entries found by the last compaction.
+    pub heartbeat_stale_entries: Gauge<i64, AtomicI64>,
+
+    /// Delay of the latest heartbeat notification after its due time.
+    pub heartbeat_lag_seconds: Gauge<i64, AtomicI64>,
+
+    /// Number of consecutive failures observed on each failed heartbeat notification.
+    pub heartbeat_failures: Histogram,
+
+    /// Number of heartbeat tokens removed after repeated failures.
+    pub heartbeat_evicted_tokens_total: Counter,
+
     /// Number of decryption failures for encrypted tokens.
     pub openpgp_decryption_failures_total: Counter,
+
+    /// Number of decrypted tokens, labeled by `fingerprint` and `status` of the key.
+    pub openpgp_decryptions_total: Family<Vec<(String, String)>, Counter>,
+
+    /// Number of encrypted tokens found in the decryption cache.
+    pub openpgp_cache_hits_total: Counter,
+
+    /// Number of encrypted tokens not found in the decryption cache.
+    pub openpgp_cache_misses_total: Counter,
+
+    /// Expiry time of the APNS certificate, 0 if unknown.
+    pub apns_certificate_expiry_timestamp_seconds: Gauge<i64, AtomicI64>,
+
+    /// Expiry time of each expiring OpenPGP key, labeled by `fingerprint`.
+    pub openpgp_key_expiry_timestamp_seconds: Family<Vec<(String, String)>, Gauge<i64, AtomicI64>>,
+
+    /// Whether each provider is configured, labeled by `provider`.
+    pub provider_enabled: Family<Vec<(String, String)>, Gauge<i64, AtomicI64>>,
 }
 
 impl Metrics {
@@ -89,6 +124,41 @@ impl Metrics {
             heartbeat_tokens.clone(),
         );
 
+        let heartbeat_expired_tokens_total = Counter::default();
+        registry.register(
+            "heartbeat_expired_tokens",
+            "Number of heartbeat tokens removed because their registration expired",
+            heartbeat_expired_tokens_total.clone(),
+        );
+
+        let heartbeat_stale_entries = Gauge::<i64, AtomicI64>::default();
+        registry.register(
+            "heartbeat_stale_entries",
+            "Number of stale schedule entries found by the last compaction",
+            heartbeat_stale_entries.clone(),
+        );
+
+        let heartbeat_lag_seconds = Gauge::<i64, AtomicI64>::default();
+        registry.register(
+            "heartbeat_lag_seconds",
+            "Delay of the latest heartbeat notification after its due time",
+            heartbeat_lag_seconds.clone(),
+        );
+
+        let heartbeat_failures = Histogram::new(exponential_buckets(1.0, 2.0, 8));
+        registry.register(
+            "heartbeat_failures",
+            "Number of consecutive failures of a token on failed heartbeat notifications",
+            heartbeat_failures.clone(),
+        );
+
+        let heartbeat_evicted_tokens_total = Counter::default();
+        registry.register(
+            "heartbeat_evicted_tokens",
+            "Number of heartbeat tokens removed after repeated failures",
+            heartbeat_evicted_tokens_total.clone(),
+        );
+
         let openpgp_decryption_failures_total = Counter::default();
         registry.register(
             "openpgp_decryption_failures",
@@ -96,6 +166,49 @@ impl Metrics {
             openpgp_decryption_failures_total.clone(),
         );
 
+        let openpgp_decryptions_total = Family::<Vec<(String, String)>, Counter>::default();
+        registry.register(
+            "openpgp_decryptions",
+            "Number of tokens decrypted with the OpenPGP key",
+            openpgp_decryptions_total.clone(),
+        );
+
+        let openpgp_cache_hits_total = Counter::default();
+        registry.register(
+            "openpgp_cache_hits",
+            "Number of encrypted tokens found in the decryption cache",
+            openpgp_cache_hits_total.clone(),
+        );
+
+        let openpgp_cache_misses_total = Counter::default();
+        registry.register(
+            "openpgp_cache_misses",
+            "Number of encrypted tokens not found in the decryption cache",
+            openpgp_cache_misses_total.clone(),
+        );
+
+        let apns_certificate_expiry_timestamp_seconds = Gauge::<i64, AtomicI64>::default();
+        registry.register(
+            "apns_certificate_expiry_timestamp_seconds",
+            "Expiry time of the APNS certificate",
+            apns_certificate_expiry_timestamp_seconds.clone(),
+        );
+
+        let openpgp_key_expiry_timestamp_seconds =
+            Family::<Vec<(String, String)>, Gauge<i64, AtomicI64>>::default();
+        registry.register(
+            "openpgp_key_expiry_timestamp_seconds",
+            "Expiry time of the OpenPGP key",
+            openpgp_key_expiry_timestamp_seconds.clone(),
+        );
+
+        let provider_enabled = Family::<Vec<(String, String)>, Gauge<i64, AtomicI64>>::default();
+        registry.register(
+            "provider_enabled",
+            "Whether the push provider is configured",
+            provider_enabled.clone(),
+        );
+
         Self {
             registry,
             direct_notifications_total,
@@ -104,11 +217,28 @@ impl Metrics {
             heartbeat_notifications_total,
             heartbeat_registrations_total,
             heartbeat_tokens,
+            heartbeat_expired_tokens_total,
+            heartbeat_stale_entries,
+            heartbeat_lag_seconds,
+            heartbeat_failures,
+            heartbeat_evicted_tokens_total,
             openpgp_decryption_failures_total,
+            openpgp_decryptions_total,
+            openpgp_cache_hits_total,
+            openpgp_cache_misses_total,
+            apns_certificate_expiry_timestamp_seconds,
+            openpgp_key_expiry_timestamp_seconds,
+            provider_enabled,
         }
     }
 }
 
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub async fn start(state: State, server: String) -> Result<()> {
     let app = axum::Router::new()
         .route("/metrics", get(metrics))
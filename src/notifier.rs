@@ -1,23 +1,139 @@
+use std::convert::TryFrom;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
+use a2::request::payload::Payload;
 use a2::{
-    Client, DefaultNotificationBuilder, Error::ResponseError, NotificationBuilder,
-    NotificationOptions, Priority,
+    DefaultNotificationBuilder, Error::ResponseError, NotificationBuilder, NotificationOptions,
+    Priority,
 };
 use anyhow::{bail, Context as _, Result};
 use log::*;
+use tokio::sync::{watch, Semaphore};
+use tokio::time::Instant;
 
-use crate::metrics::Metrics;
-use crate::schedule::Schedule;
+use crate::ratelimit;
+use crate::schedule::TokenRecord;
 use crate::server::NotificationToken;
 use crate::state::State;
 
-pub async fn start(state: State, interval: std::time::Duration) -> Result<()> {
+/// Conditions for evicting tokens
+/// which repeatedly fail to receive heartbeat notifications.
+#[derive(Debug, Clone, Copy)]
+pub struct Eviction {
+    /// Minimum number of consecutive failures.
+    pub max_failures: u32,
+
+    /// Minimum time since the first of consecutive failures.
+    pub period: Duration,
+}
+
+impl Eviction {
+    fn should_evict(&self, record: &TokenRecord) -> bool {
+        let (Some(failing_since), Some(last_attempt)) = (record.failing_since, record.last_attempt)
+        else {
+            return false;
+        };
+        record.failures >= self.max_failures
+            && last_attempt.saturating_sub(failing_since) >= self.period.as_secs()
+    }
+}
+
+/// Minimum number of heartbeat notifications per second
+/// unless limited by the maximum send rate.
+const MIN_RATE: f64 = 1.0;
+
+/// Spreads heartbeat notifications evenly over time.
+///
+/// Notifications are sent at twice the average rate
+/// needed to notify every token once per interval,
+/// so tokens that became overdue, e.g. during an outage,
+/// are caught up within half an interval without a burst.
+/// Small schedules are not paced below [`MIN_RATE`].
+#[derive(Debug)]
+struct Pacer {
+    /// Earliest time of the next notification.
+    next: Instant,
+
+    /// Maximum number of notifications per second.
+    max_rate: Option<f64>,
+}
+
+impl Pacer {
+    fn new(max_rate: Option<f64>) -> Self {
+        Self {
+            next: Instant::now(),
+            max_rate,
+        }
+    }
+
+    /// Waits until the next notification can be sent.
+    async fn ready(&self) {
+        tokio::time::sleep_until(self.next).await
+    }
+
+    /// Reserves the time slot for a sent notification.
+    fn sent(&mut self, token_count: usize, interval: Duration) {
+        let rate = (2.0 * token_count as f64 / interval.as_secs_f64().max(1.0)).max(MIN_RATE);
+        let rate = match self.max_rate {
+            Some(max_rate) => rate.min(max_rate),
+            None => rate,
+        };
+        let period = Duration::from_secs_f64(1.0 / rate);
+
+        // Slots missed while the dispatcher was idle are not caught up,
+        // so there is no burst when tokens become due.
+        let now = Instant::now();
+        self.next = self.next.max(now.checked_sub(period).unwrap_or(now)) + period;
+    }
+}
+
+/// Loads the schedule and dispatches heartbeat notifications.
+///
+/// Each token is notified `interval` after its timestamp
+/// by one of at most `workers` concurrent notification tasks,
+/// sending at most `max_rate` notifications per second.
+///
+/// Returns when `shutdown` is set
+/// after waiting up to `shutdown_timeout` for in-flight notifications.
+pub async fn start(
+    state: State,
+    interval: Duration,
+    eviction: Eviction,
+    workers: usize,
+    max_rate: Option<f64>,
+    shutdown_timeout: Duration,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
     let schedule = state.schedule();
     let metrics = state.metrics();
-    let production_client = state.production_client();
-    let sandbox_client = state.sandbox_client();
-    let topic = state.topic();
+    let pool = Arc::new(Semaphore::new(workers));
+    let mut pacer = Pacer::new(max_rate);
+    let mut paused = state.subscribe_paused();
+
+    // Load tokens in the background
+    // to start serving registrations immediately.
+    // Notifications wait until all tokens are loaded,
+    // so the most overdue tokens are notified first
+    // regardless of the order of the database.
+    let mut loader = {
+        let state = state.clone();
+        tokio::task::spawn_blocking(move || state.schedule().load())
+    };
+    let loaded = tokio::select! {
+        result = &mut loader => {
+            result?.context("Failed to load heartbeat tokens")?;
+            true
+        }
+        _ = shutdown.wait_for(|shutdown| *shutdown) => false,
+    };
+    if !loaded {
+        // The runtime waits for blocking tasks when it is dropped,
+        // so stop the loader instead of reading the whole database.
+        schedule.cancel_load();
+        let _ = loader.await;
+        return Ok(());
+    }
 
     info!(
         "Waking up devices every {}",
@@ -25,86 +141,191 @@ pub async fn start(state: State, interval: std::time::Duration) -> Result<()> {
     );
 
     loop {
-        metrics.heartbeat_tokens.set(schedule.token_count() as i64);
-
-        let Some((timestamp, token)) = schedule.pop()? else {
-            info!("No tokens to notify, sleeping for a minute.");
-            tokio::time::sleep(Duration::from_secs(60)).await;
-            continue;
+        let permit = tokio::select! {
+            permit = Arc::clone(&pool).acquire_owned() => permit?,
+            _ = shutdown.wait_for(|shutdown| *shutdown) => break,
         };
+        tokio::select! {
+            _ = pacer.ready() => {}
+            _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+        }
 
-        // Sleep until we need to notify the token.
-        let now = SystemTime::now();
-        let timestamp: SystemTime = SystemTime::UNIX_EPOCH
-            .checked_add(Duration::from_secs(timestamp))
-            .unwrap_or(now);
-        let timestamp = std::cmp::min(timestamp, now);
-        let delay = timestamp
-            .checked_add(interval)
-            .unwrap_or(now)
-            .duration_since(now)
-            .unwrap_or_default();
-
-        if !delay.is_zero() {
-            info!(
-                "Sleeping for {} before next notification.",
-                humantime::format_duration(delay)
-            );
-            tokio::time::sleep(delay).await;
+        if *paused.borrow_and_update() {
+            drop(permit);
+            info!("Heartbeat notifications are paused.");
+            tokio::select! {
+                _ = paused.wait_for(|paused| !*paused) => {
+                    info!("Heartbeat notifications are resumed.");
+                    continue;
+                }
+                _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+            }
         }
 
-        if let Err(err) = wakeup(
-            schedule,
-            metrics,
-            production_client,
-            sandbox_client,
-            topic,
-            token,
-        )
-        .await
-        {
-            error!("Failed to notify token: {err:#}");
+        // Only APNS tokens receive heartbeat notifications.
+        // APNS may be configured later by reloading the configuration.
+        if !state.provider_enabled("apns-production") {
+            drop(permit);
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(60)) => continue,
+                _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+            }
+        }
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let max_timestamp = now.saturating_sub(interval.as_secs());
+
+        // The rate limit is acquired before the token is taken,
+        // so tokens are not in flight while waiting for it.
+        let rate_limiter = state.rate_limiter();
+        let popped = schedule.pop_until(max_timestamp, |token| {
+            match token.parse::<NotificationToken>() {
+                Ok(token) => {
+                    rate_limiter.try_acquire(token.provider(), ratelimit::Priority::Heartbeat)
+                }
+                // Invalid tokens are removed without sending a notification.
+                Err(_) => Ok(()),
+            }
+        });
+        match popped {
+            Ok(Some((timestamp, token))) => {
+                let lag = max_timestamp.saturating_sub(timestamp);
+                metrics.heartbeat_lag_seconds.set(lag as i64);
+                pacer.sent(schedule.token_count(), interval);
+
+                let state = state.clone();
+                tokio::task::spawn(async move {
+                    if let Err(err) = wakeup(&state, interval, eviction, token.clone()).await {
+                        // The token stays in flight
+                        // and is rescheduled from its stored record by `compact`.
+                        error!("Failed to notify token {token}: {err:#}");
+
+                        // Keep the worker busy to avoid flooding APNS
+                        // with requests in case of database errors.
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                    }
+                    drop(permit);
+                });
+                continue;
+            }
+            Ok(None) => {}
+            Err(delay) => {
+                drop(permit);
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => continue,
+                    _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+                }
+            }
+        }
+        drop(permit);
+        metrics.heartbeat_lag_seconds.set(0);
 
-            // Sleep to avoid busy looping and flooding APNS
-            // with requests in case of database errors.
-            tokio::time::sleep(Duration::from_secs(60)).await;
+        // Sleep until the next token is due or a token is inserted.
+        // The delay is capped to recover from system clock changes.
+        let delay = match schedule.next_timestamp() {
+            Some(timestamp) => timestamp
+                .saturating_add(interval.as_secs())
+                .saturating_sub(now)
+                .min(60),
+            None => 60,
+        };
+        tokio::select! {
+            _ = schedule.inserted() => {}
+            _ = tokio::time::sleep(Duration::from_secs(delay)) => {}
+            _ = shutdown.wait_for(|shutdown| *shutdown) => break,
         }
     }
+
+    let in_flight = workers - pool.available_permits();
+    if in_flight > 0 {
+        info!("Waiting for {in_flight} heartbeat notifications to finish.");
+    }
+    let all_workers = u32::try_from(workers).unwrap_or(u32::MAX);
+    if tokio::time::timeout(shutdown_timeout, pool.acquire_many(all_workers))
+        .await
+        .is_err()
+    {
+        warn!(
+            "Heartbeat notifications did not finish within {}.",
+            humantime::format_duration(shutdown_timeout)
+        );
+    }
+    Ok(())
 }
 
-async fn wakeup(
-    schedule: &Schedule,
-    metrics: &Metrics,
-    production_client: &Client,
-    sandbox_client: &Client,
-    topic: Option<&str>,
-    key_device_token: String,
-) -> Result<()> {
-    info!("notify: {}", key_device_token);
+/// Periodically removes heartbeat tokens
+/// that were not registered again within `ttl`.
+pub async fn expire(state: State, ttl: Duration) {
+    let schedule = state.schedule();
+    let metrics = state.metrics();
 
-    let device_token: NotificationToken = key_device_token.as_str().parse()?;
+    info!(
+        "Expiring heartbeat registrations after {}",
+        humantime::format_duration(ttl)
+    );
 
-    let (client, device_token) = match device_token {
-        NotificationToken::Fcm { .. } | NotificationToken::UBports(..) => {
-            // Only APNS tokens can be registered for periodic notifications.
-            info!("Removing FCM token {key_device_token}");
-            schedule
-                .remove_token(&key_device_token)
-                .with_context(|| format!("Failed to remove {}", &key_device_token))?;
-            return Ok(());
+    // Do not read the database while it is being loaded.
+    schedule.loaded().await;
+
+    loop {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        // Expiring reads the whole database,
+        // so it does not run on an async worker thread.
+        let expired = {
+            let state = state.clone();
+            tokio::task::spawn_blocking(move || state.schedule().expire_tokens(ttl, now))
+        };
+        match expired.await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(expired)) => {
+                info!("Removed {expired} expired heartbeat tokens.");
+                metrics
+                    .heartbeat_expired_tokens_total
+                    .inc_by(expired as u64);
+            }
+            Ok(Err(err)) => error!("Failed to expire tokens: {err:#}"),
+            Err(err) => error!("Failed to expire tokens: {err}"),
         }
-        NotificationToken::ApnsSandbox(token) => (sandbox_client, token),
-        NotificationToken::ApnsProduction(token) => (production_client, token),
-    };
+        tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+    }
+}
+
+/// Periodically updates the token count
+/// and reschedules tokens stuck in flight for longer than `timeout`.
+pub async fn compact(state: State, timeout: Duration) {
+    let schedule = state.schedule();
+    let metrics = state.metrics();
+
+    loop {
+        match schedule.compact(timeout) {
+            Ok(stale) => {
+                if stale > 0 {
+                    warn!("Rescheduled {stale} stale heartbeat tokens.");
+                }
+                metrics.heartbeat_stale_entries.set(stale as i64);
+            }
+            Err(err) => error!("Failed to compact schedule: {err:#}"),
+        }
+        metrics.heartbeat_tokens.set(schedule.token_count() as i64);
+        tokio::time::sleep(Duration::from_secs(60)).await;
+    }
+}
 
-    // Send silent notification.
+/// Builds the payload of a silent heartbeat notification.
+pub(crate) fn heartbeat_payload<'a>(device_token: &'a str, topic: Option<&'a str>) -> Payload<'a> {
     // According to <https://developer.apple.com/documentation/usernotifications/generating-a-remote-notification>
     // to send a silent notification you need to set background notification flag `content-available` to 1
     // and don't include `alert`, `badge` or `sound`.
-    let payload = DefaultNotificationBuilder::new()
+    DefaultNotificationBuilder::new()
         .set_content_available()
         .build(
-            &device_token,
+            device_token,
             NotificationOptions {
                 // Normal priority (5) means
                 // "send the notification based on power considerations on the user’s device".
@@ -113,22 +334,70 @@ async fn wakeup(
                 apns_topic: topic,
                 ..Default::default()
             },
-        );
+        )
+}
 
-    match client.send(payload).await {
-        Ok(res) => match res.code {
-            200 => {
-                info!("delivered notification for {}", device_token);
-                schedule
-                    .insert_token_now(&key_device_token)
-                    .context("Failed to update latest notification timestamp")?;
-                metrics.heartbeat_notifications_total.inc();
-            }
-            _ => {
-                bail!("unexpected status: {:?}", res);
-            }
-        },
-        Err(ResponseError(res)) => {
+/// Sends a heartbeat notification to the token
+/// and records the result in the schedule.
+///
+/// The rate limit of the provider must be acquired by the caller.
+/// Failures are only recorded if the provider rejected the notification,
+/// on other errors the token is left in flight.
+pub(crate) async fn wakeup(
+    state: &State,
+    interval: Duration,
+    eviction: Eviction,
+    key_device_token: String,
+) -> Result<()> {
+    let schedule = state.schedule();
+    let metrics = state.metrics();
+    info!("notify: {}", key_device_token);
+
+    let device_token: NotificationToken = match key_device_token.parse() {
+        Ok(device_token) => device_token,
+        Err(err) => {
+            info!("Removing invalid token {key_device_token}: {err:#}");
+            schedule
+                .remove_token(&key_device_token)
+                .with_context(|| format!("Failed to remove {}", &key_device_token))?;
+            return Ok(());
+        }
+    };
+    let provider = device_token.provider();
+
+    let (client, device_token) = match device_token {
+        NotificationToken::Fcm { .. } | NotificationToken::UBports(..) => {
+            // Only APNS tokens can be registered for periodic notifications.
+            info!("Removing FCM token {key_device_token}");
+            schedule
+                .remove_token(&key_device_token)
+                .with_context(|| format!("Failed to remove {}", &key_device_token))?;
+            return Ok(());
+        }
+        NotificationToken::ApnsSandbox(token) => (state.sandbox_client(), token),
+        NotificationToken::ApnsProduction(token) => (state.production_client(), token),
+    };
+    let Some(client) = client else {
+        bail!("APNS is not configured");
+    };
+
+    let topic = state.topic();
+    let payload = heartbeat_payload(&device_token, topic.as_deref());
+
+    let err = match client.send(payload).await {
+        Ok(res) if res.code == 200 => {
+            info!("delivered notification for {}", device_token);
+            state.record_delivery(provider, Ok(()));
+            schedule
+                .record_success(&key_device_token)
+                .context("Failed to update latest notification timestamp")?;
+            metrics.heartbeat_notifications_total.inc();
+            return Ok(());
+        }
+        Ok(res) => format!("unexpected status: {res:?}"),
+        Err(ResponseError(res)) if res.code == 410 => {
+            // 410 means that "The device token is no longer active for the topic."
+            // <https://developer.apple.com/documentation/usernotifications/handling-notification-responses-from-apns>
             info!(
                 "Removing token {} due to error {:?}.",
                 &key_device_token, res
@@ -136,14 +405,97 @@ async fn wakeup(
             schedule
                 .remove_token(&key_device_token)
                 .with_context(|| format!("Failed to remove {}", &key_device_token))?;
+            return Ok(());
         }
-        Err(err) => {
-            // Update notification time regardless of success
-            // to avoid busy looping.
-            schedule
-                .insert_token_now(&key_device_token)
-                .with_context(|| format!("Failed to update token timestamp: {err:?}"))?;
-        }
+        Err(err) => format!("{err:?}"),
+    };
+
+    state.record_delivery(provider, Err(err.clone()));
+
+    // Back off the failing token instead of removing it right away,
+    // the error may be temporary.
+    let Some(record) = schedule
+        .record_failure(&key_device_token, interval)
+        .with_context(|| format!("Failed to update token timestamp: {err}"))?
+    else {
+        info!("Token {key_device_token} was removed while being notified: {err}");
+        return Ok(());
+    };
+    warn!(
+        "Failed to notify token {} ({} consecutive failures): {}",
+        &key_device_token, record.failures, err
+    );
+    metrics
+        .heartbeat_failures
+        .observe(f64::from(record.failures));
+
+    if eviction.should_evict(&record) {
+        info!(
+            "Removing token {} after {} consecutive failures.",
+            &key_device_token, record.failures
+        );
+        schedule
+            .remove_token(&key_device_token)
+            .with_context(|| format!("Failed to remove {}", &key_device_token))?;
+        metrics.heartbeat_evicted_tokens_total.inc();
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_evict() {
+        let eviction = Eviction {
+            max_failures: 3,
+            period: Duration::from_secs(100),
+        };
+        let record = |failures, failing_since, last_attempt| TokenRecord {
+            failures,
+            failing_since,
+            last_attempt,
+            ..TokenRecord::default()
+        };
+        assert!(!eviction.should_evict(&record(0, None, Some(1000))));
+        assert!(!eviction.should_evict(&record(3, None, Some(1000))));
+        assert!(!eviction.should_evict(&record(2, Some(900), Some(1000))));
+        assert!(!eviction.should_evict(&record(3, Some(901), Some(1000))));
+        assert!(eviction.should_evict(&record(3, Some(900), Some(1000))));
+        assert!(eviction.should_evict(&record(4, Some(100), Some(1000))));
+    }
+
+    #[tokio::test]
+    async fn test_pacer() {
+        let interval = Duration::from_secs(3600);
+
+        // Twice the average rate, 2 notifications per second.
+        let mut pacer = Pacer::new(None);
+        let start = pacer.next;
+        pacer.sent(3600, interval);
+        assert_eq!(pacer.next - start, Duration::from_millis(500));
+        pacer.sent(3600, interval);
+        assert_eq!(pacer.next - start, Duration::from_secs(1));
+
+        // Small schedules are paced at the minimum rate.
+        let mut pacer = Pacer::new(None);
+        let start = pacer.next;
+        pacer.sent(1, interval);
+        assert_eq!(pacer.next - start, Duration::from_secs_f64(1.0 / MIN_RATE));
+
+        // The maximum rate takes precedence over the minimum rate.
+        let mut pacer = Pacer::new(Some(0.1));
+        let start = pacer.next;
+        pacer.sent(3600, interval);
+        assert_eq!(pacer.next - start, Duration::from_secs(10));
+
+        // Slots missed while idle are not caught up.
+        let mut pacer = Pacer::new(None);
+        pacer.next = Instant::now() - interval;
+        pacer.sent(3600, interval);
+        let now = Instant::now();
+        assert!(pacer.next > now - Duration::from_millis(500));
+        assert!(pacer.next <= now + Duration::from_millis(500));
+    }
+}
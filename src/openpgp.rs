@@ -1,21 +1,329 @@
 //! Token decryption using OpenPGP.
 
+use std::collections::BTreeMap;
 use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, ensure, Context as _, Result};
 use base64::Engine as _;
-use pgp::composed::{Deserializable as _, Message, SignedSecretKey};
+use chrono::{DateTime, TimeDelta, Utc};
+use log::*;
+use pgp::composed::{
+    ArmorOptions, Deserializable as _, Esk, KeyType, Message, SecretKeyParamsBuilder,
+    SignedPublicKey, SignedPublicSubKey, SignedSecretKey, SubkeyParamsBuilder,
+};
+use pgp::crypto::ecc_curve::ECCCurve;
+use pgp::crypto::public_key::PublicKeyAlgorithm;
+use pgp::crypto::sym::SymmetricKeyAlgorithm;
+use pgp::packet;
+use pgp::ser::Serialize as _;
+use pgp::types::{
+    KeyTrait as _, PublicKeyTrait as _, PublicParams, SecretKeyTrait as _, SecretParams,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+use crate::cache::LruCache;
+use crate::config::Config;
+
+/// Status of a key in the keyring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyStatus {
+    /// Key is in use.
+    Active,
+
+    /// Key still decrypts tokens,
+    /// but clients should switch to another key.
+    Deprecated,
+
+    /// Tokens encrypted to the key are rejected.
+    Retired,
+}
+
+impl KeyStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Active => "active",
+            Self::Deprecated => "deprecated",
+            Self::Retired => "retired",
+        }
+    }
+}
+
+/// Current version of the [`Envelope`].
+const ENVELOPE_VERSION: u32 = 1;
+
+/// Allowed difference between the clocks of the client and the server.
+const MAX_CLOCK_SKEW: u64 = 5 * 60;
+
+/// Plaintext of an encrypted token.
+///
+/// Clients encrypt a JSON object like
+/// `{"version":1,"token":"<token>","issued_at":1700000000,"expires_at":1700086400,"audience":"chat.example.org"}`.
+/// Older clients encrypt the bare token,
+/// which is accepted if [`TokenPolicy::allow_legacy`] is set.
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    version: u32,
+
+    token: String,
+
+    /// Unix timestamp of token encryption.
+    issued_at: u64,
+
+    /// Unix timestamp after which the token is rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+
+    /// Domain of the chatmail server the token is intended for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    audience: Option<String>,
+}
+
+/// Wraps the token into an envelope
+/// issued now and expiring after `expires_in`.
+pub fn wrap_token(
+    token: &str,
+    audience: Option<String>,
+    expires_in: Option<Duration>,
+) -> Result<String> {
+    let issued_at = unix_now();
+    let envelope = Envelope {
+        version: ENVELOPE_VERSION,
+        token: token.to_string(),
+        issued_at,
+        expires_at: expires_in.map(|expires_in| issued_at.saturating_add(expires_in.as_secs())),
+        audience,
+    };
+    Ok(serde_json::to_string(&envelope)?)
+}
+
+/// Requirements for decrypted tokens.
+#[derive(Debug, Clone)]
+pub struct TokenPolicy {
+    /// Accept bare tokens without an [`Envelope`].
+    pub allow_legacy: bool,
+
+    /// Accepted audiences of the tokens.
+    ///
+    /// If not empty, envelopes without an audience are rejected.
+    /// If empty, the audience is not checked.
+    pub audiences: Vec<String>,
+
+    /// Maximum time since the token was issued.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for TokenPolicy {
+    fn default() -> Self {
+        Self {
+            allow_legacy: true,
+            audiences: Vec::new(),
+            max_age: None,
+        }
+    }
+}
+
+impl TokenPolicy {
+    /// Extracts the token from the decrypted content
+    /// and checks its audience.
+    ///
+    /// Returns the token, issued-at and expiry timestamps.
+    fn open(&self, content: &str) -> Result<(String, Option<u64>, Option<u64>)> {
+        // Remove the padding that is added
+        // to avoid leaking token length.
+        let content = content.trim();
+
+        if !content.starts_with('{') {
+            ensure!(self.allow_legacy, "Token without envelope is not accepted");
+            return Ok((content.to_string(), None, None));
+        }
+        let envelope: Envelope = serde_json::from_str(content).context("Invalid token envelope")?;
+        ensure!(
+            envelope.version == ENVELOPE_VERSION,
+            "Unsupported token envelope version {}",
+            envelope.version
+        );
+        if !self.audiences.is_empty() {
+            // Tokens without an audience would be accepted by any server.
+            let audience = envelope.audience.context("Token has no audience")?;
+            ensure!(
+                self.audiences.contains(&audience),
+                "Token is intended for {audience:?}"
+            );
+        }
+        Ok((
+            envelope.token,
+            Some(envelope.issued_at),
+            envelope.expires_at,
+        ))
+    }
+
+    /// Checks the issued-at and expiry timestamps of the token
+    /// against the current Unix time `now`.
+    fn check_time(&self, decrypted: &Decrypted, now: u64) -> Result<()> {
+        if let Some(expires_at) = decrypted.expires_at {
+            ensure!(now < expires_at, "Token has expired");
+        }
+        if let Some(issued_at) = decrypted.issued_at {
+            if issued_at > now.saturating_add(MAX_CLOCK_SKEW) {
+                bail!("Token is issued in the future");
+            }
+            if let Some(max_age) = self.max_age {
+                ensure!(
+                    now.saturating_sub(issued_at) <= max_age.as_secs(),
+                    "Token is too old"
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Token decrypted by [`PgpDecryptor::decrypt`].
+#[derive(Debug, Clone)]
+pub struct Decrypted {
+    pub token: String,
+
+    /// Fingerprint of the key which decrypted the token.
+    pub fingerprint: String,
+
+    /// Status of the key which decrypted the token.
+    pub status: KeyStatus,
+
+    /// True if the token was taken from the decryption cache.
+    pub cached: bool,
+
+    /// Unix timestamp of token encryption,
+    /// `None` for legacy tokens without an envelope.
+    pub issued_at: Option<u64>,
+
+    /// Unix timestamp after which the token is rejected.
+    pub expires_at: Option<u64>,
+}
+
+/// Decrypted content of a message cached by [`DecryptionCache`].
+#[derive(Debug, Clone)]
+struct CachedMessage {
+    content: String,
+
+    /// Fingerprint of the key which decrypted the message.
+    fingerprint: String,
+}
+
+/// Cache of recently decrypted messages by SHA-256 hash of the message.
+///
+/// Clients send the same encrypted token repeatedly.
+/// The cache is kept when the configuration is reloaded,
+/// so it stores the decrypted content
+/// and the token policy is applied on each use.
+pub struct DecryptionCache {
+    entries: Mutex<LruCache<[u8; 32], CachedMessage>>,
+}
+
+impl DecryptionCache {
+    /// Creates a cache of up to `capacity` messages
+    /// decrypted within `ttl`, `capacity` 0 disables caching.
+    ///
+    /// The cache is only kept in memory.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity, ttl)),
+        }
+    }
+
+    /// Changes the capacity and TTL keeping cached messages
+    /// that still fit.
+    pub fn resize(&self, capacity: usize, ttl: Duration) {
+        self.entries.lock().unwrap().resize(capacity, ttl);
+    }
+
+    fn get(&self, hash: &[u8; 32]) -> Option<CachedMessage> {
+        self.entries.lock().unwrap().get(hash, Instant::now())
+    }
+
+    fn insert(&self, hash: [u8; 32], message: CachedMessage) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(hash, message, Instant::now());
+    }
+}
+
+/// Public key published for clients encrypting tokens.
+#[derive(Debug, Clone, Serialize)]
+pub struct PublicKey {
+    pub fingerprint: String,
+
+    pub status: KeyStatus,
+
+    /// ASCII-armored public key.
+    pub armored: String,
+}
+
+/// Key of the keyring listed by [`PgpDecryptor::keys`].
+#[derive(Debug, Clone)]
+pub struct KeyInfo {
+    pub fingerprint: String,
+
+    pub status: KeyStatus,
+
+    /// Creation time as a Unix timestamp.
+    pub created_at: i64,
+
+    /// Earliest expiry time of the primary key
+    /// and its encryption subkeys as a Unix timestamp,
+    /// `None` if none of them expires.
+    pub expires_at: Option<i64>,
+}
 
 /// OpenPGP message decryptor.
 pub struct PgpDecryptor {
-    /// Keyring of keys used for decryption.
+    /// Keyring of keys used for decryption,
+    /// unlocked if they are protected with a passphrase.
     keyring: Vec<SignedSecretKey>,
+
+    /// Indices of the keys in the keyring
+    /// by key ID of the primary key and the subkeys.
+    key_ids: BTreeMap<Vec<u8>, usize>,
+
+    /// Hex fingerprints of the keys in the keyring.
+    fingerprints: Vec<String>,
+
+    /// Statuses of the keys in the keyring.
+    statuses: Vec<KeyStatus>,
+
+    /// Public keys derived from the keyring.
+    public_keys: Vec<SignedPublicKey>,
+
+    /// ASCII-armored public keys.
+    armored_public_keys: Vec<String>,
+
+    /// Recently decrypted messages,
+    /// shared with decryptors of reloaded configurations.
+    cache: Arc<DecryptionCache>,
+
+    /// Requirements for decrypted tokens.
+    policy: TokenPolicy,
 }
 
 impl PgpDecryptor {
     /// Creates a new OpenPGP decryptor
     /// with the given secret keys.
-    pub fn new(keyring_armor: &str) -> Result<Self> {
+    ///
+    /// `deprecated` and `retired` are fingerprints of keys
+    /// with the corresponding [`KeyStatus`].
+    /// `passphrases` maps fingerprints of protected keys to their passphrases.
+    ///
+    /// Fails if any key cannot be unlocked.
+    pub fn new(
+        keyring_armor: &str,
+        deprecated: &[String],
+        retired: &[String],
+        passphrases: &BTreeMap<String, String>,
+    ) -> Result<Self> {
         let cursor = Cursor::new(keyring_armor);
         let (mut secret_keys_iter, _headers) = pgp::composed::signed_key::from_armor_many(cursor)?;
         let mut secret_keys: Vec<SignedSecretKey> = Vec::new();
@@ -24,24 +332,589 @@ impl PgpDecryptor {
                 secret_keys.push(key.into_secret());
             }
         }
+
+        let deprecated: Vec<String> = deprecated.iter().map(|f| normalize(f)).collect();
+        let retired: Vec<String> = retired.iter().map(|f| normalize(f)).collect();
+        let passphrases: BTreeMap<String, &String> = passphrases
+            .iter()
+            .map(|(fingerprint, passphrase)| (normalize(fingerprint), passphrase))
+            .collect();
+        let fingerprints: Vec<String> = secret_keys.iter().map(hex_fingerprint).collect();
+        for fingerprint in deprecated.iter().chain(&retired).chain(passphrases.keys()) {
+            if !fingerprints.contains(fingerprint) {
+                warn!("OpenPGP key {fingerprint} is not in the keyring.");
+            }
+        }
+        let statuses = fingerprints
+            .iter()
+            .map(|fingerprint| {
+                if retired.contains(fingerprint) {
+                    KeyStatus::Retired
+                } else if deprecated.contains(fingerprint) {
+                    KeyStatus::Deprecated
+                } else {
+                    KeyStatus::Active
+                }
+            })
+            .collect();
+
+        // Keys are unlocked once,
+        // passphrases are not kept.
+        let mut keyring = Vec::with_capacity(secret_keys.len());
+        let mut key_ids = BTreeMap::new();
+        for (index, (key, fingerprint)) in secret_keys.iter().zip(&fingerprints).enumerate() {
+            let passphrase = passphrases
+                .get(fingerprint)
+                .map_or("", |passphrase| passphrase.as_str());
+            let key = unlock(key, passphrase)
+                .with_context(|| format!("Failed to unlock OpenPGP key {fingerprint}"))?;
+            key_ids.insert(key.key_id().as_ref().to_vec(), index);
+            for subkey in &key.secret_subkeys {
+                key_ids.insert(subkey.key.key_id().as_ref().to_vec(), index);
+            }
+            keyring.push(key);
+        }
+
+        let public_keys: Vec<SignedPublicKey> = secret_keys
+            .iter()
+            .map(|key| SignedPublicKey::from(key.clone()))
+            .collect();
+        let armored_public_keys = public_keys
+            .iter()
+            .map(|key| {
+                key.to_armored_string(ArmorOptions::default())
+                    .context("Failed to armor public key")
+            })
+            .collect::<Result<_>>()?;
+
         Ok(Self {
-            keyring: secret_keys,
+            keyring,
+            key_ids,
+            fingerprints,
+            statuses,
+            public_keys,
+            armored_public_keys,
+            cache: Arc::new(DecryptionCache::new(0, Duration::ZERO)),
+            policy: TokenPolicy::default(),
         })
     }
 
+    /// Creates the decryptor for the configured keyring,
+    /// `None` if no keyring is configured.
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        let Some(ref keyring_path) = config.openpgp_keyring_path else {
+            return Ok(None);
+        };
+        let keyring = std::fs::read_to_string(keyring_path)
+            .with_context(|| format!("Failed to read keyring {}", keyring_path.display()))?;
+        let decryptor = Self::new(
+            &keyring,
+            &config.openpgp_deprecated_keys,
+            &config.openpgp_retired_keys,
+            &config.openpgp_passphrases()?,
+        )?
+        .with_policy(config.openpgp_token_policy());
+        Ok(Some(decryptor))
+    }
+
+    /// Caches decrypted tokens in `cache`.
+    pub fn with_cache(mut self, cache: Arc<DecryptionCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Sets the requirements for decrypted tokens.
+    pub fn with_policy(mut self, policy: TokenPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Returns the hex fingerprints of the keys
+    /// and their expiry times as Unix timestamps,
+    /// `None` for keys that do not expire.
+    ///
+    /// See [`KeyInfo::expires_at`].
+    pub fn key_expiry(&self) -> Vec<(String, Option<i64>)> {
+        self.public_keys
+            .iter()
+            .zip(&self.fingerprints)
+            .map(|(key, fingerprint)| (fingerprint.clone(), expires_at(key)))
+            .collect()
+    }
+
+    /// Returns the keys of the keyring.
+    pub fn keys(&self) -> Vec<KeyInfo> {
+        self.keyring
+            .iter()
+            .zip(&self.public_keys)
+            .zip(&self.fingerprints)
+            .zip(&self.statuses)
+            .map(|(((key, public_key), fingerprint), &status)| KeyInfo {
+                fingerprint: fingerprint.clone(),
+                status,
+                created_at: key.created_at().timestamp(),
+                expires_at: expires_at(public_key),
+            })
+            .collect()
+    }
+
+    /// Encrypts the plaintext to the first active key
+    /// into a base64-encoded OpenPGP message
+    /// as sent by clients in `openpgp:` tokens.
+    ///
+    /// Expired encryption subkeys are skipped.
+    pub fn encrypt(&self, plaintext: &str) -> Result<String> {
+        let index = self
+            .statuses
+            .iter()
+            .position(|&status| status == KeyStatus::Active)
+            .context("No active key in the keyring")?;
+        let now = unix_now() as i64;
+        let subkey = self.public_keys[index]
+            .public_subkeys
+            .iter()
+            .filter(|subkey| subkey.is_encryption_key())
+            .find(|subkey| {
+                subkey_expires_at(subkey).is_none_or(|expires_at| expires_at.timestamp() > now)
+            })
+            .with_context(|| {
+                format!(
+                    "OpenPGP key {} has no unexpired encryption subkey",
+                    self.fingerprints[index]
+                )
+            })?;
+        let msg = Message::new_literal("", plaintext).encrypt_to_keys_seipdv1(
+            &mut rand::thread_rng(),
+            SymmetricKeyAlgorithm::AES128,
+            &[subkey],
+        )?;
+        Ok(base64::engine::general_purpose::STANDARD.encode(msg.to_bytes()?))
+    }
+
+    /// Returns the public keys which clients may encrypt tokens to,
+    /// active keys first.
+    ///
+    /// Retired keys are not published.
+    pub fn public_keys(&self) -> Vec<PublicKey> {
+        let mut public_keys: Vec<PublicKey> = self
+            .fingerprints
+            .iter()
+            .zip(&self.statuses)
+            .zip(&self.armored_public_keys)
+            .filter(|((_, &status), _)| status != KeyStatus::Retired)
+            .map(|((fingerprint, &status), armored)| PublicKey {
+                fingerprint: fingerprint.clone(),
+                status,
+                armored: armored.clone(),
+            })
+            .collect();
+        public_keys.sort_by_key(|key| key.status != KeyStatus::Active);
+        public_keys
+    }
+
     /// Decrypts incoming token from an base64-encoded OpenPGP message.
-    pub fn decrypt(&self, message: &str) -> Result<String> {
+    ///
+    /// Tokens encrypted to retired keys are decrypted as well,
+    /// the caller is responsible for rejecting them.
+    pub fn decrypt(&self, message: &str) -> Result<Decrypted> {
+        let hash: [u8; 32] = Sha256::digest(message.as_bytes()).into();
+        let cached = self.cache.get(&hash).and_then(|cached| {
+            // The key may have been removed from the keyring by reloading.
+            let index = self
+                .fingerprints
+                .iter()
+                .position(|fingerprint| *fingerprint == cached.fingerprint)?;
+            Some((index, cached.content))
+        });
+        let (index, content, cached) = match cached {
+            Some((index, content)) => (index, content, true),
+            None => {
+                let (index, content) = self.decrypt_uncached(message)?;
+                let cached = CachedMessage {
+                    content: content.clone(),
+                    fingerprint: self.fingerprints[index].clone(),
+                };
+                self.cache.insert(hash, cached);
+                (index, content, false)
+            }
+        };
+
+        // The policy and key statuses may change by reloading,
+        // so they are applied to cached tokens as well.
+        let (token, issued_at, expires_at) = self.policy.open(&content)?;
+        let decrypted = Decrypted {
+            token,
+            fingerprint: self.fingerprints[index].clone(),
+            status: self.statuses[index],
+            cached,
+            issued_at,
+            expires_at,
+        };
+        self.policy.check_time(&decrypted, unix_now())?;
+        Ok(decrypted)
+    }
+
+    /// Decrypts the message
+    /// and returns the index of the key which decrypted it
+    /// and the decrypted content.
+    fn decrypt_uncached(&self, message: &str) -> Result<(usize, String)> {
         let bytes = base64::engine::general_purpose::STANDARD.decode(message)?;
         let cursor = Cursor::new(bytes);
         let msg = Message::from_bytes(cursor)?;
-        let secret_key_refs: Vec<&SignedSecretKey> = self.keyring.iter().collect();
-        let (msg, _key_ids) = msg.decrypt(|| "".into(), &secret_key_refs)?;
+
+        let index = self.recipient(&msg)?;
+        let (msg, _key_ids) = msg.decrypt(String::new, &[&self.keyring[index]])?;
         let content = msg.get_content()?.unwrap_or_default();
-        let token = String::from_utf8(content)?;
+        Ok((index, String::from_utf8(content)?))
+    }
 
-        // Remove the padding that is added
-        // to avoid leaking token length.
-        let token = token.trim().to_string();
-        Ok(token)
+    /// Returns the index of the key in the keyring
+    /// which the message is encrypted to.
+    ///
+    /// If the message is encrypted to several keys,
+    /// e.g. during a key rotation,
+    /// active keys are preferred over deprecated and retired keys.
+    fn recipient(&self, msg: &Message) -> Result<usize> {
+        let Message::Encrypted { ref esk, .. } = *msg else {
+            bail!("Token is not an encrypted message");
+        };
+        esk.iter()
+            .filter_map(|esk| match esk {
+                Esk::PublicKeyEncryptedSessionKey(pkesk) => {
+                    self.key_ids.get(pkesk.id().as_ref()).copied()
+                }
+                _ => None,
+            })
+            .min_by_key(|&index| match self.statuses[index] {
+                KeyStatus::Active => 0,
+                KeyStatus::Deprecated => 1,
+                KeyStatus::Retired => 2,
+            })
+            .context("Token is not encrypted to a key in the keyring")
+    }
+}
+
+/// Generates a secret key for token decryption
+/// with an encryption subkey
+/// and returns it ASCII-armored.
+///
+/// The key is protected with `passphrase` if given.
+pub fn generate_key(user_id: &str, passphrase: Option<String>) -> Result<String> {
+    let mut rng = rand::thread_rng();
+    let subkey = SubkeyParamsBuilder::default()
+        .key_type(KeyType::ECDH(ECCCurve::Curve25519))
+        .can_encrypt(true)
+        .passphrase(passphrase.clone())
+        .build()
+        .map_err(|err| anyhow!("{err}"))?;
+    let params = SecretKeyParamsBuilder::default()
+        .key_type(KeyType::EdDSALegacy)
+        .can_certify(true)
+        .can_sign(true)
+        .primary_user_id(user_id.to_string())
+        .passphrase(passphrase.clone())
+        .subkey(subkey)
+        .build()
+        .map_err(|err| anyhow!("{err}"))?;
+    let key = params
+        .generate(&mut rng)
+        .context("Failed to generate key")?;
+    let key = key
+        .sign(&mut rng, || passphrase.unwrap_or_default())
+        .context("Failed to sign key")?;
+    key.to_armored_string(ArmorOptions::default())
+        .context("Failed to armor key")
+}
+
+/// Returns the earliest expiry time of the primary key
+/// and its encryption subkeys as a Unix timestamp.
+///
+/// Tokens are decrypted with the subkeys,
+/// which often expire before the primary key.
+fn expires_at(key: &SignedPublicKey) -> Option<i64> {
+    key.public_subkeys
+        .iter()
+        .filter(|subkey| subkey.is_encryption_key())
+        .filter_map(subkey_expires_at)
+        .chain(key.expires_at())
+        .min()
+        .map(|expires_at| expires_at.timestamp())
+}
+
+/// Returns the expiry time of the subkey
+/// set by its latest binding signature.
+fn subkey_expires_at(subkey: &SignedPublicSubKey) -> Option<DateTime<Utc>> {
+    let signature = subkey
+        .signatures
+        .iter()
+        .max_by_key(|signature| signature.created())?;
+    let expiration = *signature.key_expiration_time()?;
+    // Zero expiration time means that the key does not expire.
+    if expiration <= TimeDelta::zero() {
+        return None;
+    }
+    Some(*subkey.key.created_at() + expiration)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Returns a copy of the key
+/// with the primary key and all subkeys unlocked with the passphrase.
+///
+/// Decrypting with the unlocked key
+/// does not repeat the key derivation of the passphrase.
+fn unlock(key: &SignedSecretKey, passphrase: &str) -> Result<SignedSecretKey> {
+    let mut unlocked = key.clone();
+    let primary_key = &key.primary_key;
+    if let Some(params) = unlock_params(
+        primary_key.secret_params(),
+        passphrase,
+        primary_key.algorithm(),
+        primary_key.public_params(),
+    )? {
+        unlocked.primary_key = packet::SecretKey::new(primary_key.public_key(), params);
+    }
+    for subkey in &mut unlocked.secret_subkeys {
+        if let Some(params) = unlock_params(
+            subkey.key.secret_params(),
+            passphrase,
+            subkey.key.algorithm(),
+            subkey.key.public_params(),
+        )? {
+            subkey.key = packet::SecretSubkey::new(subkey.key.public_key(), params);
+        }
+    }
+    Ok(unlocked)
+}
+
+/// Decrypts encrypted secret key material,
+/// `None` if it is not encrypted.
+fn unlock_params(
+    params: &SecretParams,
+    passphrase: &str,
+    algorithm: PublicKeyAlgorithm,
+    public_params: &PublicParams,
+) -> Result<Option<SecretParams>> {
+    match params {
+        SecretParams::Plain(_) => Ok(None),
+        SecretParams::Encrypted(params) => {
+            let plain = params.unlock(|| passphrase.to_string(), algorithm, public_params)?;
+            Ok(Some(SecretParams::Plain(plain)))
+        }
+    }
+}
+
+/// Normalizes a hex fingerprint given in the configuration.
+fn normalize(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase()
+}
+
+/// Returns the fingerprint of the key as an uppercase hex string.
+fn hex_fingerprint(key: &SignedSecretKey) -> String {
+    key.fingerprint()
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decrypted(issued_at: Option<u64>, expires_at: Option<u64>) -> Decrypted {
+        Decrypted {
+            token: "token".to_string(),
+            fingerprint: "AB".to_string(),
+            status: KeyStatus::Active,
+            cached: false,
+            issued_at,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn test_token_policy() -> Result<()> {
+        let policy = TokenPolicy {
+            allow_legacy: true,
+            audiences: vec!["chat.example.org".to_string()],
+            max_age: Some(Duration::from_secs(3600)),
+        };
+        assert_eq!(policy.open("token  \n")?, ("token".to_string(), None, None));
+        assert_eq!(
+            policy.open(
+                r#"{"version":1,"token":"token","issued_at":1000,"expires_at":2000,"audience":"chat.example.org"}  "#
+            )?,
+            ("token".to_string(), Some(1000), Some(2000))
+        );
+        assert!(policy
+            .open(r#"{"version":1,"token":"token","issued_at":1000,"audience":"other.org"}"#)
+            .is_err());
+        assert!(policy
+            .open(r#"{"version":2,"token":"token","issued_at":1000}"#)
+            .is_err());
+
+        // Envelopes without an audience are rejected if audiences are configured.
+        assert!(policy
+            .open(r#"{"version":1,"token":"token","issued_at":1000}"#)
+            .is_err());
+
+        let strict = TokenPolicy {
+            allow_legacy: false,
+            ..TokenPolicy::default()
+        };
+        assert!(strict.open("token").is_err());
+        assert!(strict
+            .open(r#"{"version":1,"token":"token","issued_at":1000,"audience":"other.org"}"#)
+            .is_ok());
+
+        assert!(strict
+            .open(r#"{"version":1,"token":"token","issued_at":1000}"#)
+            .is_ok());
+
+        let wrapped = wrap_token(
+            "token",
+            Some("chat.example.org".to_string()),
+            Some(Duration::from_secs(60)),
+        )?;
+        let (token, Some(issued_at), Some(expires_at)) = policy.open(&wrapped)? else {
+            panic!("envelope without timestamps");
+        };
+        assert_eq!(token, "token");
+        assert_eq!(expires_at - issued_at, 60);
+
+        assert!(policy.check_time(&decrypted(None, None), 1000).is_ok());
+        assert!(policy
+            .check_time(&decrypted(Some(1000), Some(2000)), 1500)
+            .is_ok());
+        assert!(policy
+            .check_time(&decrypted(Some(1000), Some(2000)), 2000)
+            .is_err());
+        assert!(policy
+            .check_time(&decrypted(Some(1000), None), 5000)
+            .is_err());
+        assert!(policy
+            .check_time(&decrypted(Some(2000), None), 1000)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() -> Result<()> {
+        for passphrase in [None, Some("passphrase".to_string())] {
+            let armored = generate_key("test@example.org", passphrase.clone())?;
+            let (key, _headers) = SignedSecretKey::from_string(&armored)?;
+            let fingerprint = hex_fingerprint(&key);
+            let passphrases: BTreeMap<String, String> = passphrase
+                .iter()
+                .map(|passphrase| (fingerprint.clone(), passphrase.clone()))
+                .collect();
+
+            let decryptor = PgpDecryptor::new(&armored, &[], &[], &passphrases)?;
+            let message = decryptor.encrypt("token")?;
+            let decrypted = decryptor.decrypt(&message)?;
+            assert_eq!(decrypted.token, "token");
+            assert_eq!(decrypted.fingerprint, fingerprint);
+            assert_eq!(decrypted.status, KeyStatus::Active);
+
+            if passphrase.is_some() {
+                // The key cannot be unlocked without the passphrase.
+                assert!(PgpDecryptor::new(&armored, &[], &[], &BTreeMap::new()).is_err());
+            }
+        }
+        Ok(())
+    }
+
+    /// Encrypts the plaintext to the keys of the keyring at `indices`.
+    fn encrypt_to(decryptor: &PgpDecryptor, indices: &[usize], plaintext: &str) -> Result<String> {
+        let subkeys: Vec<&SignedPublicSubKey> = indices
+            .iter()
+            .filter_map(|&index| {
+                decryptor.public_keys[index]
+                    .public_subkeys
+                    .iter()
+                    .find(|subkey| subkey.is_encryption_key())
+            })
+            .collect();
+        let msg = Message::new_literal("", plaintext).encrypt_to_keys_seipdv1(
+            &mut rand::thread_rng(),
+            SymmetricKeyAlgorithm::AES128,
+            &subkeys,
+        )?;
+        Ok(base64::engine::general_purpose::STANDARD.encode(msg.to_bytes()?))
+    }
+
+    /// Returns the fingerprint of the armored secret key.
+    fn fingerprint(armored: &str) -> Result<String> {
+        let (key, _headers) = SignedSecretKey::from_string(armored)?;
+        Ok(hex_fingerprint(&key))
+    }
+
+    #[test]
+    fn test_key_status() -> Result<()> {
+        let deprecated = generate_key("deprecated@example.org", None)?;
+        let retired = generate_key("retired@example.org", None)?;
+        let active = generate_key("active@example.org", None)?;
+        let keyring = format!("{deprecated}\n{retired}\n{active}");
+
+        // Fingerprints are matched ignoring case and whitespace.
+        let deprecated_fingerprint = fingerprint(&deprecated)?;
+        let spaced = deprecated_fingerprint
+            .as_bytes()
+            .chunks(4)
+            .map(|chunk| String::from_utf8_lossy(chunk).to_lowercase())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let retired_fingerprints = [fingerprint(&retired)?];
+        let decryptor =
+            PgpDecryptor::new(&keyring, &[spaced], &retired_fingerprints, &BTreeMap::new())?;
+
+        let decrypted = decryptor.decrypt(&encrypt_to(&decryptor, &[0], "token")?)?;
+        assert_eq!(decrypted.fingerprint, deprecated_fingerprint);
+        assert_eq!(decrypted.status, KeyStatus::Deprecated);
+        let decrypted = decryptor.decrypt(&encrypt_to(&decryptor, &[1], "token")?)?;
+        assert_eq!(decrypted.fingerprint, retired_fingerprints[0]);
+        assert_eq!(decrypted.status, KeyStatus::Retired);
+        let decrypted = decryptor.decrypt(&encrypt_to(&decryptor, &[2], "token")?)?;
+        assert_eq!(decrypted.status, KeyStatus::Active);
+
+        // Retired keys are not published, active keys are published first.
+        let public_keys = decryptor.public_keys();
+        let published: Vec<(&str, KeyStatus)> = public_keys
+            .iter()
+            .map(|key| (key.fingerprint.as_str(), key.status))
+            .collect();
+        assert_eq!(
+            published,
+            [
+                (fingerprint(&active)?.as_str(), KeyStatus::Active),
+                (deprecated_fingerprint.as_str(), KeyStatus::Deprecated),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_recipient() -> Result<()> {
+        let old = generate_key("old@example.org", None)?;
+        let new = generate_key("new@example.org", None)?;
+        let retired = [fingerprint(&old)?];
+        let decryptor =
+            PgpDecryptor::new(&format!("{old}\n{new}"), &[], &retired, &BTreeMap::new())?;
+
+        // Clients may encrypt to the retired key along with the new key.
+        let decrypted = decryptor.decrypt(&encrypt_to(&decryptor, &[0, 1], "token")?)?;
+        assert_eq!(decrypted.token, "token");
+        assert_eq!(decrypted.status, KeyStatus::Active);
+        assert_ne!(decrypted.fingerprint, retired[0]);
+        Ok(())
     }
 }
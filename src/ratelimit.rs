@@ -0,0 +1,246 @@
+//! Limits of the rate of notifications sent to each push provider.
+//!
+//! Each provider has a token bucket
+//! shared by direct notifications and heartbeats.
+//! Part of the bucket is reserved for direct notifications,
+//! so they are sent first when the limit is reached.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context as _, Error, Result};
+
+use crate::server::PROVIDERS;
+
+/// Fraction of the bucket capacity
+/// which can only be used by direct notifications.
+const DIRECT_RESERVE: f64 = 0.5;
+
+/// Minimum notification rate per second which can be configured.
+///
+/// Lower rates would make the waiting time overflow.
+pub const MIN_RATE: f64 = 0.001;
+
+/// Priority of a notification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Notification requested with `/notify`.
+    Direct,
+
+    /// Periodic heartbeat notification.
+    Heartbeat,
+}
+
+/// Rate limit of a provider given on the command line
+/// as `<provider>=<notifications per second>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    /// Provider name, e.g. `apns-production`.
+    pub provider: String,
+
+    /// Maximum number of notifications per second.
+    pub rate: f64,
+}
+
+impl RateLimit {
+    pub fn new(provider: &str, rate: f64) -> Result<Self> {
+        if !PROVIDERS.contains(&provider) {
+            bail!(
+                "Unknown provider {provider:?}, expected one of {}",
+                PROVIDERS.join(", ")
+            );
+        }
+        if !(rate >= MIN_RATE && rate.is_finite()) {
+            bail!("Rate must be at least {MIN_RATE}");
+        }
+        Ok(Self {
+            provider: provider.to_string(),
+            rate,
+        })
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((provider, rate)) = s.split_once('=') else {
+            bail!("Invalid rate limit {s:?}, expected <provider>=<rate>");
+        };
+        let rate: f64 = rate
+            .parse()
+            .with_context(|| format!("Invalid rate {rate:?}"))?;
+        Self::new(provider, rate)
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Number of notifications per second.
+    rate: f64,
+
+    /// Maximum number of tokens in the bucket,
+    /// notifications which can be sent in a burst.
+    capacity: f64,
+
+    tokens: f64,
+
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64) -> Self {
+        // Allow bursts of one second worth of notifications.
+        let capacity = rate.max(1.0);
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Adds the tokens accumulated since the last update.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Changes the rate keeping the current tokens,
+    /// so changing the limit does not allow a burst.
+    fn set_rate(&mut self, rate: f64, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+        self.capacity = rate.max(1.0);
+        self.tokens = self.tokens.min(self.capacity);
+    }
+
+    /// Takes a token from the bucket
+    /// or returns the time to wait until it can be taken.
+    fn take(&mut self, priority: Priority, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+
+        let required = match priority {
+            Priority::Direct => 1.0,
+            // Small buckets cannot reserve anything.
+            Priority::Heartbeat => (1.0 + self.capacity * DIRECT_RESERVE).min(self.capacity),
+        };
+        if self.tokens >= required {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (required - self.tokens) / self.rate,
+            ))
+        }
+    }
+}
+
+/// Rate limiter of the notifications to all providers.
+///
+/// Providers without a configured limit are not limited.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &[RateLimit]) -> Self {
+        let limiter = Self::default();
+        limiter.set_limits(limits);
+        limiter
+    }
+
+    /// Replaces the limits, e.g. when the configuration is reloaded.
+    ///
+    /// Buckets of providers which stay limited keep their tokens,
+    /// so reloading does not reset the limits.
+    pub fn set_limits(&self, limits: &[RateLimit]) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|provider, _| limits.iter().any(|limit| limit.provider == *provider));
+        for limit in limits {
+            match buckets.get_mut(&limit.provider) {
+                Some(bucket) => bucket.set_rate(limit.rate, now),
+                None => {
+                    buckets.insert(limit.provider.clone(), Bucket::new(limit.rate));
+                }
+            }
+        }
+    }
+
+    /// Reserves sending a notification to the provider
+    /// or returns the time to wait before trying again.
+    pub fn try_acquire(&self, provider: &str, priority: Priority) -> Result<(), Duration> {
+        match self.buckets.lock().unwrap().get_mut(provider) {
+            Some(bucket) => bucket.take(priority, Instant::now()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(4.0);
+        bucket.updated = now;
+
+        // Heartbeats leave half of the bucket to direct notifications.
+        assert!(bucket.take(Priority::Heartbeat, now).is_ok());
+        assert!(bucket.take(Priority::Heartbeat, now).is_ok());
+        assert_eq!(
+            bucket.take(Priority::Heartbeat, now),
+            Err(Duration::from_millis(250))
+        );
+        assert!(bucket.take(Priority::Direct, now).is_ok());
+        assert!(bucket.take(Priority::Direct, now).is_ok());
+        assert_eq!(
+            bucket.take(Priority::Direct, now),
+            Err(Duration::from_millis(250))
+        );
+
+        // Bucket is refilled over time.
+        let now = now + Duration::from_millis(500);
+        assert!(bucket.take(Priority::Direct, now).is_ok());
+        assert!(bucket.take(Priority::Direct, now).is_ok());
+        assert!(bucket.take(Priority::Direct, now).is_err());
+    }
+
+    #[test]
+    fn test_rate_limit_parse() -> Result<()> {
+        assert_eq!(
+            "apns-production=100".parse::<RateLimit>()?,
+            RateLimit {
+                provider: "apns-production".to_string(),
+                rate: 100.0
+            }
+        );
+        assert!("apns=100".parse::<RateLimit>().is_err());
+        assert!("fcm=0".parse::<RateLimit>().is_err());
+        assert!("fcm=1e-300".parse::<RateLimit>().is_err());
+        assert!("fcm=inf".parse::<RateLimit>().is_err());
+        assert!("fcm".parse::<RateLimit>().is_err());
+
+        // Providers without limits are not limited.
+        let limiter = RateLimiter::new(&["fcm=1".parse()?]);
+        assert!(limiter.try_acquire("fcm", Priority::Direct).is_ok());
+        assert!(limiter.try_acquire("fcm", Priority::Direct).is_err());
+        assert!(limiter.try_acquire("ubports", Priority::Direct).is_ok());
+
+        // Updating the limits does not refill the buckets.
+        limiter.set_limits(&["fcm=1".parse()?, "ubports=1".parse()?]);
+        assert!(limiter.try_acquire("fcm", Priority::Direct).is_err());
+        assert!(limiter.try_acquire("ubports", Priority::Direct).is_ok());
+        assert!(limiter.try_acquire("ubports", Priority::Direct).is_err());
+        limiter.set_limits(&[]);
+        assert!(limiter.try_acquire("fcm", Priority::Direct).is_ok());
+        Ok(())
+    }
+}
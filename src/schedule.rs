@@ -1,98 +1,627 @@
-use std::cmp::Reverse;
-use std::collections::BinaryHeap;
+use std::collections::{BTreeMap, HashSet};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
-use std::time::SystemTime;
+use std::time::Instant;
+use std::time::{Duration, SystemTime};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context as _, Result};
+use log::*;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, Notify};
+
+use crate::encryption::{EncryptedStorage, EncryptionKey};
+use crate::index::{Index, Shard};
+use crate::server::NotificationToken;
+use crate::storage::{self, Backend, Storage};
+
+/// Upper bound for the heartbeat backoff of failing tokens.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Version of the [`TokenRecord`] encoding stored in the database.
+///
+/// Encoded records start with the version byte
+/// followed by the JSON serialization of the record.
+const RECORD_VERSION: u8 = 1;
+
+/// Per-token state stored in the database.
+///
+/// New fields must have a default value
+/// so records written by older versions can still be decoded.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TokenRecord {
+    /// Timestamp from which the next heartbeat notification is scheduled.
+    pub timestamp: u64,
+
+    /// Timestamp of the latest registration.
+    pub registered_at: u64,
+
+    /// Timestamp of the latest successful heartbeat notification.
+    pub last_success: Option<u64>,
+
+    /// Timestamp of the latest heartbeat notification attempt.
+    pub last_attempt: Option<u64>,
+
+    /// Number of consecutive failed heartbeat notification attempts.
+    pub failures: u32,
+
+    /// Timestamp of the first of consecutive failed attempts.
+    pub failing_since: Option<u64>,
+
+    /// Push provider of the token, e.g. `apns-production`.
+    pub provider: Option<String>,
+
+    /// Heartbeat interval in seconds overriding the default interval.
+    ///
+    /// Reserved for per-token intervals, not set or used yet.
+    pub interval: Option<u64>,
+
+    /// Metadata provided by the client, e.g. the app version.
+    ///
+    /// Reserved for client metadata, not set or used yet.
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl TokenRecord {
+    fn new(token: &str, now: u64) -> Self {
+        let provider = token
+            .parse::<NotificationToken>()
+            .ok()
+            .map(|token| token.provider().to_string());
+        Self {
+            timestamp: now,
+            registered_at: now,
+            provider,
+            ..Default::default()
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![RECORD_VERSION];
+        serde_json::to_writer(&mut buf, self)?;
+        Ok(buf)
+    }
+
+    /// Decodes a stored record.
+    ///
+    /// Values stored by older versions are 8-byte big-endian notification timestamps,
+    /// such tokens are considered registered at `now`.
+    fn decode(token: &str, value: &[u8], now: u64) -> Result<Self> {
+        if is_legacy(value) {
+            return Ok(Self {
+                timestamp: read_u64(value),
+                ..Self::new(token, now)
+            });
+        }
+        match value.split_first() {
+            Some((&RECORD_VERSION, record)) => Ok(serde_json::from_slice(record)?),
+            Some((version, _)) => bail!("Unknown token record version {version}"),
+            None => bail!("Empty token record"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Schedule {
-    /// Database to persist tokens and latest notification time.
-    db: sled::Db,
+    /// Database to persist tokens and their [`TokenRecord`].
+    db: Box<dyn Storage>,
+
+    /// Index of tokens prioritized by the latest notification timestamp.
+    ///
+    /// Tokens which are being notified are tracked as in flight
+    /// until the notification result is recorded.
+    index: Index,
+
+    /// Notified when a token is inserted into the index.
+    inserted: Notify,
+
+    /// Tokens updated or removed while the schedule is loaded,
+    /// `None` after [`Schedule::load`] has finished.
+    ///
+    /// Database entries of these tokens read by the loader
+    /// may be outdated and are skipped.
+    touched: Mutex<Option<HashSet<String>>>,
+
+    /// Set by [`Schedule::cancel_load`] to stop loading.
+    load_cancelled: AtomicBool,
 
-    /// Min-heap of tokens prioritized by the latest notification timestamp.
-    heap: Mutex<BinaryHeap<(Reverse<u64>, String)>>,
+    /// Set when [`Schedule::load`] has finished, successfully or not.
+    loaded: watch::Sender<bool>,
 }
 
 impl Schedule {
+    /// Opens the schedule in a sled database.
     pub fn new(db_path: &Path) -> Result<Self> {
-        let db = sled::open(db_path)?;
-        let mut heap = BinaryHeap::new();
-        for entry in db.iter() {
+        Self::open(Backend::Sled, db_path)
+    }
+
+    /// Opens the schedule in a database of the given backend.
+    ///
+    /// Tokens are not scheduled until [`Schedule::load`] is called.
+    pub fn open(backend: Backend, db_path: &Path) -> Result<Self> {
+        Ok(Self::with_storage(storage::open(backend, db_path)?))
+    }
+
+    /// Opens the schedule in a database of the given backend
+    /// encrypted with `key`.
+    ///
+    /// Tokens are not scheduled until [`Schedule::load`] is called.
+    pub fn open_encrypted(backend: Backend, db_path: &Path, key: &EncryptionKey) -> Result<Self> {
+        let db = EncryptedStorage::new(storage::open(backend, db_path)?, key);
+        Ok(Self::with_storage(Box::new(db)))
+    }
+
+    /// Creates a schedule of tokens stored in `db`.
+    ///
+    /// Tokens are not scheduled until [`Schedule::load`] is called.
+    pub fn with_storage(db: Box<dyn Storage>) -> Self {
+        Self {
+            db,
+            index: Index::new(),
+            inserted: Notify::new(),
+            touched: Mutex::new(Some(HashSet::new())),
+            load_cancelled: AtomicBool::new(false),
+            loaded: watch::Sender::new(false),
+        }
+    }
+
+    /// Schedules all tokens stored in the database.
+    ///
+    /// The schedule can be used while it is loading,
+    /// so this is usually run in the background
+    /// to serve registrations without waiting for the whole database to be read.
+    /// Tokens are loaded in storage order,
+    /// so they should not be notified before loading has finished.
+    ///
+    /// Returns the number of loaded tokens.
+    pub fn load(&self) -> Result<usize> {
+        let start = Instant::now();
+        let result = self.load_entries();
+
+        // Stop tracking updated tokens even if loading failed,
+        // the set would grow with every update otherwise.
+        *self.touched.lock().unwrap() = None;
+        self.loaded.send_replace(true);
+
+        let loaded = result?;
+        info!(
+            "Loaded {loaded} tokens in {}.",
+            humantime::format_duration(start.elapsed())
+        );
+        Ok(loaded)
+    }
+
+    fn load_entries(&self) -> Result<usize> {
+        let now = now();
+        let mut loaded = 0;
+        for entry in self.db.iter() {
+            if self.load_cancelled.load(Ordering::Relaxed) {
+                bail!("Loading was cancelled");
+            }
             let (key, value) = entry?;
-            let token = String::from_utf8(key.to_vec()).unwrap();
-
-            let timestamp = if let Some(value) = value.get(..8) {
-                let mut buf: [u8; 8] = [0; 8];
-                buf.copy_from_slice(&value[..8]);
-                u64::from_be_bytes(buf)
-            } else {
-                0
+            // Keys of encrypted databases are hashes.
+            let token = String::from_utf8(key)
+                .context("Invalid token in the database, the database may be encrypted")?;
+
+            let mut shard = self.index.shard(&token);
+            if self.is_touched(&token) {
+                continue;
+            }
+            let record = match TokenRecord::decode(&token, &value, now) {
+                Ok(record) => record,
+                Err(err) => {
+                    warn!("Skipping token {token}: {err:#}");
+                    continue;
+                }
             };
-            heap.push((Reverse(timestamp), token))
+            if is_legacy(&value) {
+                self.db.insert(token.as_bytes(), &record.encode()?)?;
+            }
+            shard.insert(&token, record.timestamp);
+            self.inserted.notify_one();
+            loaded += 1;
         }
-        let heap = Mutex::new(heap);
-        Ok(Self { db, heap })
+        Ok(loaded)
+    }
+
+    /// Stops [`Schedule::load`] before the next entry,
+    /// e.g. to shut down without reading the whole database.
+    pub fn cancel_load(&self) {
+        self.load_cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true until [`Schedule::load`] has finished.
+    pub fn is_loading(&self) -> bool {
+        !*self.loaded.borrow()
+    }
+
+    /// Waits until [`Schedule::load`] has finished.
+    pub async fn loaded(&self) {
+        let mut loaded = self.loaded.subscribe();
+        // The sender is owned by the schedule and is not dropped meanwhile.
+        let _ = loaded.wait_for(|loaded| *loaded).await;
     }
 
-    /// Registers a new heartbeat notification token.
+    /// Returns true if the token was updated or removed
+    /// since the schedule started loading.
     ///
-    /// This should also be called after successful notification
-    /// to update latest notification time.
-    pub fn insert_token(&self, token: &str, now: u64) -> Result<()> {
-        self.db.insert(token.as_bytes(), &u64::to_be_bytes(now))?;
-        let mut heap = self.heap.lock().unwrap();
-        heap.push((Reverse(now), token.to_owned()));
+    /// Should be called while holding the lock of the token shard.
+    fn is_touched(&self, token: &str) -> bool {
+        self.touched
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|touched| touched.contains(token))
+    }
+
+    /// Marks the token as updated or removed
+    /// if the schedule is still loading.
+    ///
+    /// Should be called while holding the lock of the token shard.
+    fn touch(&self, token: &str) {
+        if let Some(touched) = self.touched.lock().unwrap().as_mut() {
+            touched.insert(token.to_string());
+        }
+    }
+
+    /// Returns the stored record of the token.
+    pub fn get(&self, token: &str) -> Result<Option<TokenRecord>> {
+        let Some(value) = self.db.get(token.as_bytes())? else {
+            return Ok(None);
+        };
+        Ok(Some(TokenRecord::decode(token, &value, now())?))
+    }
+
+    /// Updates the record of the token
+    /// and schedules it at the resulting record timestamp.
+    ///
+    /// If the token is not in the database yet
+    /// or its record cannot be decoded,
+    /// a new record registered at `now` is created.
+    fn upsert(&self, token: &str, now: u64, f: impl FnOnce(&mut TokenRecord)) -> Result<()> {
+        let mut shard = self.index.shard(token);
+        let mut record = match self.db.get(token.as_bytes())? {
+            Some(value) => TokenRecord::decode(token, &value, now).unwrap_or_else(|err| {
+                warn!("Replacing invalid record of token {token}: {err:#}");
+                TokenRecord::new(token, now)
+            }),
+            None => TokenRecord::new(token, now),
+        };
+        f(&mut record);
+        self.store(&mut shard, token, &record)
+    }
+
+    /// Updates the record of a stored token
+    /// and schedules it at the resulting record timestamp.
+    ///
+    /// Returns the updated record
+    /// or `None` if the token is not in the database,
+    /// e.g. because it was removed while being notified.
+    fn update(
+        &self,
+        token: &str,
+        now: u64,
+        f: impl FnOnce(&mut TokenRecord),
+    ) -> Result<Option<TokenRecord>> {
+        let mut shard = self.index.shard(token);
+        let Some(value) = self.db.get(token.as_bytes())? else {
+            shard.remove(token);
+            return Ok(None);
+        };
+        let mut record = TokenRecord::decode(token, &value, now)?;
+        f(&mut record);
+        self.store(&mut shard, token, &record)?;
+        Ok(Some(record))
+    }
+
+    /// Stores the record and schedules the token.
+    ///
+    /// `shard` must be the locked shard of the token.
+    fn store(&self, shard: &mut Shard, token: &str, record: &TokenRecord) -> Result<()> {
+        self.db.insert(token.as_bytes(), &record.encode()?)?;
+        shard.insert(token, record.timestamp);
+        self.touch(token);
+        self.inserted.notify_one();
         Ok(())
     }
 
+    /// Registers a token for heartbeat notifications
+    /// and records the registration time.
+    ///
+    /// Registrations that are not refreshed
+    /// are eventually removed by [`Schedule::expire_tokens`].
+    pub fn register_token(&self, token: &str, now: u64) -> Result<()> {
+        self.upsert(token, now, |record| {
+            record.timestamp = now;
+            record.registered_at = now;
+            record.failures = 0;
+            record.failing_since = None;
+        })
+    }
+
+    pub fn register_token_now(&self, token: &str) -> Result<()> {
+        let now = now();
+        self.upsert(token, now, |record| {
+            record.timestamp = jittered(now);
+            record.registered_at = now;
+            record.failures = 0;
+            record.failing_since = None;
+        })
+    }
+
+    /// Registers a new heartbeat notification token
+    /// or reschedules a registered token at `now`.
+    pub fn insert_token(&self, token: &str, now: u64) -> Result<()> {
+        self.upsert(token, now, |record| record.timestamp = now)
+    }
+
     pub fn insert_token_now(&self, token: &str) -> Result<()> {
-        let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
-        let mut rng = rand::thread_rng();
-        let jitter = rng.gen_range(0..120);
-        self.insert_token(token, now.saturating_sub(60).saturating_add(jitter))
+        let now = now();
+        self.upsert(token, now, |record| record.timestamp = jittered(now))
     }
 
-    pub async fn flush(&self) -> Result<()> {
-        self.db.flush_async().await?;
+    /// Records a successful heartbeat notification
+    /// and schedules the next notification.
+    ///
+    /// Tokens removed meanwhile are not registered again.
+    pub fn record_success(&self, token: &str) -> Result<()> {
+        let now = now();
+        self.update(token, now, |record| {
+            record.timestamp = jittered(now);
+            record.last_attempt = Some(now);
+            record.last_success = Some(now);
+            record.failures = 0;
+            record.failing_since = None;
+        })?;
         Ok(())
     }
 
+    /// Records a failed heartbeat notification
+    /// and schedules the next notification with [`backoff`].
+    ///
+    /// Returns the updated record
+    /// or `None` if the token was removed meanwhile.
+    pub fn record_failure(&self, token: &str, interval: Duration) -> Result<Option<TokenRecord>> {
+        let now = now();
+        self.update(token, now, |record| {
+            record.failures = record.failures.saturating_add(1);
+            record.failing_since.get_or_insert(now);
+            record.last_attempt = Some(now);
+            record.timestamp =
+                jittered(now).saturating_add(backoff(interval, record.failures).as_secs());
+        })
+    }
+
+    /// Stores the record of the token as is,
+    /// replacing the current record,
+    /// and schedules the token at the record timestamp.
+    pub fn import_record(&self, token: &str, record: TokenRecord) -> Result<()> {
+        self.upsert(token, record.registered_at, |stored| *stored = record)
+    }
+
+    /// Iterates over all tokens stored in the database and their records.
+    ///
+    /// Records which cannot be read are returned as errors next to their token,
+    /// so they can be skipped, while errors of the database end the iteration.
+    pub fn records(&self) -> impl Iterator<Item = Result<(String, Result<TokenRecord>)>> + '_ {
+        let now = now();
+        self.db.iter().map(move |entry| {
+            let (key, value) = entry?;
+            let entry = match String::from_utf8(key) {
+                Ok(token) => {
+                    let record = TokenRecord::decode(&token, &value, now)
+                        .with_context(|| format!("Invalid record of token {token}"));
+                    (token, record)
+                }
+                Err(err) => (
+                    String::from_utf8_lossy(err.as_bytes()).into_owned(),
+                    Err(anyhow!("Invalid token in the database")),
+                ),
+            };
+            Ok(entry)
+        })
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.db.flush().await
+    }
+
+    /// Copies all tokens to another storage.
+    ///
+    /// Returns the number of copied tokens.
+    pub async fn copy_to(&self, to: &dyn Storage) -> Result<usize> {
+        storage::copy(self.db.as_ref(), to).await
+    }
+
     /// Removes token from the schedule.
     pub fn remove_token(&self, token: &str) -> Result<()> {
-        self.db.remove(token)?;
+        let mut shard = self.index.shard(token);
+        self.db.remove(token.as_bytes())?;
+        shard.remove(token);
+        self.touch(token);
         Ok(())
     }
 
-    pub fn pop(&self) -> Result<Option<(u64, String)>> {
-        let mut heap = self.heap.lock().unwrap();
-        loop {
-            let Some((timestamp, token)) = heap.pop() else {
-                return Ok(None);
-            };
-            let Some(value) = self.db.get(token.as_bytes())? else {
-                // Token was removed from the database already.
+    /// Removes tokens which were not registered again within `ttl`.
+    ///
+    /// Returns the number of removed tokens.
+    pub fn expire_tokens(&self, ttl: Duration, now: u64) -> Result<usize> {
+        let mut expired = 0;
+        for entry in self.db.iter() {
+            let (key, value) = entry?;
+            let Ok(token) = String::from_utf8(key) else {
+                warn!("Skipping invalid token in the database.");
                 continue;
             };
-            if timestamp.0.to_be_bytes() != *value {
-                // Token was reinserted with a different timestamp,
-                // e.g. by reregistration.
+            let record = match TokenRecord::decode(&token, &value, now) {
+                Ok(record) => record,
+                Err(err) => {
+                    warn!("Skipping token {token}: {err:#}");
+                    continue;
+                }
+            };
+            if record.registered_at.saturating_add(ttl.as_secs()) >= now {
                 continue;
             }
-            return Ok(Some((timestamp.0, token)));
+
+            // Check again while holding the lock
+            // in case the token was registered again meanwhile.
+            let mut shard = self.index.shard(&token);
+            match self.get(&token) {
+                Ok(Some(record)) if record.registered_at.saturating_add(ttl.as_secs()) < now => {
+                    self.db.remove(token.as_bytes())?;
+                    shard.remove(&token);
+                    self.touch(&token);
+                    expired += 1;
+                }
+                Ok(_) => {}
+                Err(err) => warn!("Skipping token {token}: {err:#}"),
+            }
+        }
+        Ok(expired)
+    }
+
+    /// Takes the token with the earliest timestamp out of the schedule.
+    ///
+    /// The token stays in the database
+    /// and is scheduled again when the notification result is recorded.
+    pub fn pop(&self) -> Result<Option<(u64, String)>> {
+        Ok(self
+            .index
+            .pop()
+            .map(|(timestamp, token)| (timestamp, token.to_string())))
+    }
+
+    /// Schedules again tokens which were popped
+    /// but not updated or removed within `timeout`,
+    /// e.g. because recording the notification result failed.
+    ///
+    /// Tokens whose record cannot be read stay in flight
+    /// and are retried on the next call.
+    ///
+    /// Returns the number of such stale entries.
+    pub fn compact(&self, timeout: Duration) -> Result<usize> {
+        let mut stale = 0;
+        for token in self.index.stale(timeout) {
+            let mut shard = self.index.shard(&token);
+            if !shard.is_stale(&token, timeout) {
+                // Token was updated meanwhile.
+                continue;
+            }
+            match self.get(&token) {
+                Ok(Some(record)) => {
+                    shard.insert(&token, record.timestamp);
+                    self.inserted.notify_one();
+                }
+                Ok(None) => shard.remove(&token),
+                Err(err) => {
+                    warn!("Failed to reschedule token {token}: {err:#}");
+                    continue;
+                }
+            }
+            stale += 1;
+        }
+        Ok(stale)
+    }
+
+    /// Takes the token with the earliest timestamp out of the schedule
+    /// if its timestamp is not later than `max_timestamp`
+    /// and `admit` accepts the token.
+    ///
+    /// If `admit` returns an error, e.g. because of a rate limit,
+    /// the token stays scheduled and the error is returned.
+    pub fn pop_until<E>(
+        &self,
+        max_timestamp: u64,
+        admit: impl FnMut(&str) -> Result<(), E>,
+    ) -> Result<Option<(u64, String)>, E> {
+        Ok(self
+            .index
+            .pop_until(max_timestamp, admit)?
+            .map(|(timestamp, token)| (timestamp, token.to_string())))
+    }
+
+    /// Takes the token out of the schedule
+    /// to notify it ahead of its timestamp
+    /// if `admit` accepts the token.
+    ///
+    /// Returns `None` if the token is not scheduled
+    /// or is already being notified.
+    /// If `admit` returns an error, the token stays scheduled.
+    pub fn take<E>(
+        &self,
+        token: &str,
+        admit: impl FnOnce(&str) -> Result<(), E>,
+    ) -> Result<Option<u64>, E> {
+        let mut shard = self.index.shard(token);
+        if !shard.is_queued(token) {
+            return Ok(None);
         }
+        admit(token)?;
+        Ok(shard.take(token))
+    }
+
+    /// Returns the earliest timestamp of the scheduled tokens.
+    pub fn next_timestamp(&self) -> Option<u64> {
+        self.index.first()
     }
 
-    /// Returns the number of tokens in the schedule.
+    /// Waits until a token is scheduled.
+    ///
+    /// Completes immediately if a token was scheduled
+    /// since the last call completed.
+    pub async fn inserted(&self) {
+        self.inserted.notified().await
+    }
+
+    /// Returns the number of distinct tokens in the schedule,
+    /// including tokens which are currently being notified.
     pub fn token_count(&self) -> usize {
-        let heap = self.heap.lock().unwrap();
-        heap.len()
+        self.index.len()
+    }
+}
+
+/// Returns true if the value is stored in the legacy format
+/// predating [`TokenRecord`].
+fn is_legacy(value: &[u8]) -> bool {
+    value.len() == 8
+}
+
+fn read_u64(value: &[u8]) -> u64 {
+    let mut buf: [u8; 8] = [0; 8];
+    if let Some(value) = value.get(..8) {
+        buf.copy_from_slice(value);
     }
+    u64::from_be_bytes(buf)
+}
+
+/// Returns current UNIX timestamp in seconds.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Returns the delay added to the heartbeat interval
+/// of a token after `failures` consecutive failures.
+///
+/// The total delay between notifications doubles with each failure
+/// up to [`MAX_BACKOFF`].
+pub fn backoff(interval: Duration, failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.min(16)) - 1;
+    interval.saturating_mul(factor).min(MAX_BACKOFF)
+}
+
+/// Returns a timestamp around `now`
+/// with a random jitter of up to a minute in each direction
+/// to spread notifications.
+fn jittered(now: u64) -> u64 {
+    let mut rng = rand::thread_rng();
+    let jitter = rng.gen_range(0..120);
+    now.saturating_sub(60).saturating_add(jitter)
 }
 
 #[cfg(test)]
@@ -106,6 +635,7 @@ mod tests {
         let dir = tempdir()?;
         let db_path = dir.path().join("db.sled");
         let schedule = Schedule::new(&db_path)?;
+        schedule.load()?;
         assert_eq!(schedule.token_count(), 0);
 
         schedule.insert_token("foo", 10)?;
@@ -122,16 +652,19 @@ mod tests {
         // Reopen to test persistence.
         drop(schedule);
         let schedule = Schedule::new(&db_path)?;
+        schedule.load()?;
         assert_eq!(schedule.token_count(), 2);
 
         let (second_timestamp, second_token) = schedule.pop()?.unwrap();
         assert_eq!(second_timestamp, 20);
         assert_eq!(second_token, "bar");
-        assert_eq!(schedule.token_count(), 1);
+        // Popped token is still counted.
+        assert_eq!(schedule.token_count(), 2);
 
         // Simulate restart or crash, token "bar" was not reinserted or removed by the app.
         drop(schedule);
         let schedule = Schedule::new(&db_path)?;
+        schedule.load()?;
         assert_eq!(schedule.token_count(), 2);
 
         // Token "bar" is still there.
@@ -147,6 +680,7 @@ mod tests {
         let dir = tempdir()?;
         let db_path = dir.path().join("db.sled");
         let schedule = Schedule::new(&db_path)?;
+        schedule.load()?;
         assert_eq!(schedule.token_count(), 0);
 
         schedule.insert_token("foo", 10)?;
@@ -155,19 +689,263 @@ mod tests {
         assert_eq!(schedule.token_count(), 3);
 
         schedule.insert_token("bar", 50)?;
-        // There are two items for token "bar", but old one is invalid now
-        // because the database contains different timestamp.
-        // It will be dropped when encountered.
-        assert_eq!(schedule.token_count(), 4);
+        // Old entry for token "bar" is replaced.
+        assert_eq!(schedule.token_count(), 3);
 
         assert_eq!(schedule.pop()?.unwrap(), (10, "foo".to_string()));
+        assert_eq!(schedule.pop()?.unwrap(), (30, "baz".to_string()));
+        assert_eq!(schedule.pop()?.unwrap(), (50, "bar".to_string()));
+        assert_eq!(schedule.pop()?, None);
+        // Tokens being notified are still registered.
         assert_eq!(schedule.token_count(), 3);
 
-        assert_eq!(schedule.pop()?.unwrap(), (30, "baz".to_string()));
-        // Invalid "bar" entry is also dropped before we reach "baz" entry.
+        schedule.insert_token("foo", 60)?;
+        schedule.remove_token("baz")?;
+        assert_eq!(schedule.token_count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact() -> Result<()> {
+        let dir = tempdir()?;
+        let schedule = Schedule::new(&dir.path().join("db.sled"))?;
+        schedule.load()?;
+
+        schedule.insert_token("foo", 10)?;
+        schedule.insert_token("bar", 20)?;
+        assert_eq!(schedule.pop()?.unwrap(), (10, "foo".to_string()));
+        assert_eq!(schedule.pop()?.unwrap(), (20, "bar".to_string()));
+        assert_eq!(schedule.compact(Duration::from_secs(60))?, 0);
+
+        // Token "bar" was removed from the database without updating the index.
+        schedule.db.remove(b"bar")?;
+        assert_eq!(schedule.compact(Duration::ZERO)?, 2);
         assert_eq!(schedule.token_count(), 1);
+        assert_eq!(schedule.pop()?.unwrap(), (10, "foo".to_string()));
+        Ok(())
+    }
 
-        assert_eq!(schedule.pop()?.unwrap(), (50, "bar".to_string()));
+    #[test]
+    fn test_expire_tokens() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("db.sled");
+        let schedule = Schedule::new(&db_path)?;
+        schedule.load()?;
+        let ttl = Duration::from_secs(100);
+
+        schedule.register_token("foo", 10)?;
+        schedule.register_token("bar", 20)?;
+        assert_eq!(schedule.expire_tokens(ttl, 100)?, 0);
+
+        // "foo" is refreshed, "bar" is not.
+        schedule.register_token("foo", 110)?;
+        assert_eq!(schedule.expire_tokens(ttl, 150)?, 1);
+
+        // Heartbeat notifications do not refresh the registration.
+        schedule.insert_token("foo", 200)?;
+        assert_eq!(schedule.expire_tokens(ttl, 250)?, 1);
+
+        assert_eq!(schedule.pop()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_records() -> Result<()> {
+        let dir = tempdir()?;
+        let schedule = Schedule::new(&dir.path().join("db.sled"))?;
+        schedule.db.insert(b"foo", &[0xff])?;
+        schedule.db.insert(&[0xff], &[0xff])?;
+        schedule.register_token("bar", 10)?;
+
+        // Invalid records do not stop expiring other tokens.
+        assert_eq!(schedule.expire_tokens(Duration::from_secs(100), 200)?, 1);
+        assert!(schedule.get("foo").is_err());
+
+        // Registering the token again replaces its invalid record.
+        schedule.register_token("foo", 300)?;
+        assert_eq!(schedule.get("foo")?.unwrap().registered_at, 300);
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_legacy_format() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("db.sled");
+        {
+            let db = sled::open(&db_path)?;
+            db.insert("foo", &u64::to_be_bytes(10))?;
+            db.insert("sandbox:bar", &u64::to_be_bytes(20))?;
+        }
+
+        let schedule = Schedule::new(&db_path)?;
+        schedule.load()?;
+        let record = schedule.get("foo")?.unwrap();
+        assert_eq!(record.timestamp, 10);
+        assert!(record.registered_at > 10);
+        assert_eq!(record.provider.as_deref(), Some("apns-production"));
+
+        let record = schedule.get("sandbox:bar")?.unwrap();
+        assert_eq!(record.timestamp, 20);
+        assert!(record.registered_at > 20);
+        assert_eq!(record.provider.as_deref(), Some("apns-sandbox"));
+
+        assert_eq!(schedule.pop()?.unwrap(), (10, "foo".to_string()));
+        assert_eq!(schedule.pop()?.unwrap(), (20, "sandbox:bar".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_failures() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("db.sled");
+        let schedule = Schedule::new(&db_path)?;
+        schedule.load()?;
+        let interval = Duration::from_secs(1000);
+
+        schedule.register_token("foo", 10)?;
+        let record = schedule.record_failure("foo", interval)?.unwrap();
+        assert_eq!(record.failures, 1);
+        assert_eq!(record.registered_at, 10);
+        assert_eq!(record.last_success, None);
+        let failing_since = record.failing_since.unwrap();
+        assert!(record.timestamp >= failing_since + 1000 - 60);
+
+        let record = schedule.record_failure("foo", interval)?.unwrap();
+        assert_eq!(record.failures, 2);
+        assert_eq!(record.failing_since, Some(failing_since));
+        assert!(record.timestamp >= failing_since + 3000 - 60);
+        assert_eq!(schedule.get("foo")?.unwrap(), record);
+
+        schedule.record_success("foo")?;
+        let record = schedule.get("foo")?.unwrap();
+        assert_eq!(record.failures, 0);
+        assert_eq!(record.failing_since, None);
+        assert_eq!(record.last_success, record.last_attempt);
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_removed_token() -> Result<()> {
+        let dir = tempdir()?;
+        let schedule = Schedule::new(&dir.path().join("db.sled"))?;
+
+        schedule.register_token("foo", 10)?;
+        schedule.register_token("bar", 20)?;
+        assert_eq!(schedule.pop()?.unwrap(), (10, "foo".to_string()));
+        assert_eq!(schedule.pop()?.unwrap(), (20, "bar".to_string()));
+
+        // Tokens removed while being notified are not registered again.
+        schedule.remove_token("foo")?;
+        schedule.remove_token("bar")?;
+        schedule.record_success("foo")?;
+        assert_eq!(
+            schedule.record_failure("bar", Duration::from_secs(60))?,
+            None
+        );
+        assert_eq!(schedule.get("foo")?, None);
+        assert_eq!(schedule.get("bar")?, None);
+        assert_eq!(schedule.token_count(), 0);
+        assert_eq!(schedule.pop()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_take() -> Result<()> {
+        let dir = tempdir()?;
+        let schedule = Schedule::new(&dir.path().join("db.sled"))?;
+        schedule.load()?;
+
+        schedule.register_token("foo", 10)?;
+        schedule.register_token("bar", 20)?;
+        assert_eq!(schedule.take("baz", |_| Ok::<_, ()>(())), Ok(None));
+
+        // Tokens which are not admitted stay scheduled.
+        assert_eq!(schedule.take("bar", |_| Err(())), Err(()));
+        assert_eq!(schedule.take("bar", |_| Ok::<_, ()>(())), Ok(Some(20)));
+
+        // Taken tokens are in flight and cannot be taken again.
+        assert_eq!(schedule.take("bar", |_| Ok::<_, ()>(())), Ok(None));
+        assert_eq!(schedule.token_count(), 2);
+        assert_eq!(schedule.pop()?.unwrap(), (10, "foo".to_string()));
+        assert_eq!(schedule.pop()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff() {
+        let interval = Duration::from_secs(20 * 60);
+        assert_eq!(backoff(interval, 0), Duration::ZERO);
+        assert_eq!(backoff(interval, 1), interval);
+        assert_eq!(backoff(interval, 2), interval * 3);
+        assert_eq!(backoff(interval, 3), interval * 7);
+        assert_eq!(backoff(interval, 10), MAX_BACKOFF);
+        assert_eq!(backoff(interval, u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_open_encrypted() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("db.sqlite");
+        let key = EncryptionKey::new(&[1; 32])?;
+        {
+            let schedule = Schedule::open_encrypted(Backend::Sqlite, &db_path, &key)?;
+            schedule.load()?;
+            schedule.register_token("foo", 10)?;
+        }
+
+        let schedule = Schedule::open_encrypted(Backend::Sqlite, &db_path, &key)?;
+        schedule.load()?;
+        assert_eq!(schedule.get("foo")?.unwrap().registered_at, 10);
+        assert_eq!(schedule.pop()?.unwrap(), (10, "foo".to_string()));
+
+        // Encrypted database cannot be loaded without the key.
+        drop(schedule);
+        let schedule = Schedule::open(Backend::Sqlite, &db_path)?;
+        assert!(schedule.load().is_err());
+
+        // Updates are not tracked after loading failed.
+        schedule.register_token("bar", 20)?;
+        assert!(schedule.touched.lock().unwrap().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("db.sled");
+        {
+            let db = sled::open(&db_path)?;
+            db.insert("foo", &u64::to_be_bytes(10))?;
+            db.insert("bar", &u64::to_be_bytes(20))?;
+            db.insert("baz", &u64::to_be_bytes(30))?;
+        }
+
+        let schedule = Schedule::new(&db_path)?;
+        assert_eq!(schedule.token_count(), 0);
+
+        // Tokens updated or removed before loading are not overwritten.
+        schedule.insert_token("foo", 40)?;
+        schedule.remove_token("bar")?;
+        assert_eq!(schedule.token_count(), 1);
+
+        assert!(schedule.is_loading());
+        assert_eq!(schedule.load()?, 1);
+        assert!(!schedule.is_loading());
+        assert_eq!(schedule.pop()?.unwrap(), (30, "baz".to_string()));
+        assert_eq!(schedule.pop()?.unwrap(), (40, "foo".to_string()));
+        assert_eq!(schedule.pop()?, None);
+
+        // Legacy values are converted when loaded.
+        let value = schedule.db.get(b"baz")?.unwrap();
+        assert!(!is_legacy(&value));
+        assert_eq!(schedule.get("baz")?.unwrap().timestamp, 30);
+        drop(schedule);
+
+        // Cancelled loading stops before the next entry.
+        let schedule = Schedule::new(&db_path)?;
+        schedule.cancel_load();
+        assert!(schedule.load().is_err());
+        assert!(!schedule.is_loading());
         assert_eq!(schedule.token_count(), 0);
         Ok(())
     }
@@ -0,0 +1,157 @@
+//! Sending a single notification for debugging delivery to a token.
+
+use std::fmt;
+
+use a2::Error::ResponseError;
+use anyhow::{bail, Context as _, Result};
+
+use crate::notifier::heartbeat_payload;
+use crate::openpgp::Decrypted;
+use crate::server::{
+    alert_payload, fcm_request, is_valid_token, ubports_request, NotificationToken,
+};
+use crate::state::State;
+
+/// Outcome of a sent notification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The provider accepted the notification.
+    Delivered,
+
+    /// The provider rejected the token,
+    /// the server would respond with 410 Gone.
+    Rejected,
+
+    /// The notification failed, e.g. due to a provider error.
+    Failed,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let outcome = match self {
+            Self::Delivered => "delivered",
+            Self::Rejected => "token rejected",
+            Self::Failed => "failed",
+        };
+        f.write_str(outcome)
+    }
+}
+
+/// Result of [`send`].
+#[derive(Debug)]
+pub struct Report {
+    /// Decryption result for `openpgp:` tokens.
+    pub decrypted: Option<Decrypted>,
+
+    /// Provider the notification was sent to.
+    pub provider: &'static str,
+
+    /// Request sent to the provider.
+    pub request: String,
+
+    /// Full response of the provider.
+    pub response: String,
+
+    pub outcome: Outcome,
+}
+
+/// Sends a visible notification or, if `heartbeat` is set,
+/// a heartbeat notification to the token.
+///
+/// Unlike the server, the schedule is not modified
+/// and tokens encrypted to retired keys are not rejected.
+pub async fn send(state: &State, token: &str, heartbeat: bool) -> Result<Report> {
+    let (token, decrypted) = match token.strip_prefix("openpgp:") {
+        Some(encrypted) => {
+            let decryptor = state
+                .openpgp_decryptor()
+                .context("OpenPGP is not configured")?;
+            let decrypted = decryptor
+                .decrypt(encrypted)
+                .context("Failed to decrypt token")?;
+            (decrypted.token.clone(), Some(decrypted))
+        }
+        None => (token.to_string(), None),
+    };
+
+    let token: NotificationToken = token.parse()?;
+    let provider = token.provider();
+    if !state.provider_enabled(provider) {
+        bail!("{provider} is not configured");
+    }
+
+    let (request, response, outcome) = match token {
+        NotificationToken::UBports(_) | NotificationToken::Fcm { .. } if heartbeat => {
+            bail!("Heartbeat notifications are only sent to APNS tokens")
+        }
+        NotificationToken::UBports(ref token) => {
+            if !is_valid_token(token) {
+                bail!("Invalid UBports token");
+            }
+            let (request, body) = ubports_request(state.fcm_client(), token);
+            let (response, outcome) = send_http(request).await?;
+            (body, response, outcome)
+        }
+        NotificationToken::Fcm { ref token, .. } => {
+            if !is_valid_token(token) {
+                bail!("Invalid FCM token");
+            }
+            let fcm_api_key = state
+                .fcm_token()
+                .await?
+                .context("FCM authenticator returned no token")?;
+            let (request, body) = fcm_request(state.fcm_client(), &fcm_api_key, token);
+            let (response, outcome) = send_http(request).await?;
+            (body, response, outcome)
+        }
+        NotificationToken::ApnsSandbox(ref token)
+        | NotificationToken::ApnsProduction(ref token) => {
+            let client = match provider {
+                "apns-sandbox" => state.sandbox_client(),
+                _ => state.production_client(),
+            }
+            .context("APNS is not configured")?;
+            let topic = state.topic();
+            let payload = if heartbeat {
+                heartbeat_payload(token, topic.as_deref())
+            } else {
+                alert_payload(token, topic.as_deref())
+            };
+            let request = serde_json::to_string(&payload)?;
+            let (response, outcome) = match client.send(payload).await {
+                Ok(res) if res.code == 200 => (format!("{res:?}"), Outcome::Delivered),
+                Ok(res) => (format!("{res:?}"), Outcome::Failed),
+                Err(ResponseError(res)) if res.code == 410 => {
+                    (format!("{res:?}"), Outcome::Rejected)
+                }
+                Err(err) => (format!("{err:?}"), Outcome::Failed),
+            };
+            (request, response, outcome)
+        }
+    };
+
+    Ok(Report {
+        decrypted,
+        provider,
+        request,
+        response,
+        outcome,
+    })
+}
+
+/// Sends the request to UBports or FCM
+/// and classifies the response like the server does.
+async fn send_http(request: reqwest::RequestBuilder) -> Result<(String, Outcome)> {
+    let res = request.send().await?;
+    let status = res.status();
+    let headers = format!("{:?}", res.headers());
+    let body = res.text().await.unwrap_or_default();
+    let outcome = if status.is_client_error() {
+        Outcome::Rejected
+    } else if status.is_success() {
+        Outcome::Delivered
+    } else {
+        Outcome::Failed
+    };
+    Ok((format!("{status}\n{headers}\n{body}"), outcome))
+}
@@ -1,28 +1,46 @@
+use a2::request::payload::Payload;
 use a2::{
     DefaultNotificationBuilder, Error::ResponseError, NotificationBuilder, NotificationOptions,
     Priority, PushType,
 };
-use anyhow::{bail, Error, Result};
-use axum::http::StatusCode;
+use anyhow::{bail, Context as _, Error, Result};
+use axum::http::{header, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use chrono::{Local, TimeDelta};
 use log::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
 use std::str::FromStr;
+use std::time::Duration;
 
 use crate::metrics::Metrics;
+use crate::openpgp::{KeyStatus, PgpDecryptor, PublicKey};
+use crate::ratelimit;
 use crate::state::State;
 
-pub async fn start(state: State, server: String, port: u16) -> Result<()> {
-    let app = axum::Router::new()
+/// Serves requests until `shutdown` completes
+/// and all requests being handled are finished.
+pub async fn start(
+    state: State,
+    server: String,
+    port: u16,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind((server, port)).await?;
+    axum::serve(listener, router(state))
+        .with_graceful_shutdown(shutdown)
+        .await?;
+    Ok(())
+}
+
+fn router(state: State) -> axum::Router {
+    axum::Router::new()
         .route("/", get(|| async { "Hello, world!" }))
         .route("/register", post(register_device))
         .route("/notify", post(notify_device))
-        .with_state(state);
-    let listener = tokio::net::TcpListener::bind((server, port)).await?;
-    axum::serve(listener, app).await?;
-    Ok(())
+        .route("/openpgp-key", get(openpgp_key))
+        .with_state(state)
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -30,7 +48,7 @@ struct DeviceQuery {
     token: String,
 }
 
-struct AppError(anyhow::Error);
+pub(crate) struct AppError(anyhow::Error);
 
 impl<E> From<E> for AppError
 where
@@ -51,29 +69,129 @@ impl IntoResponse for AppError {
     }
 }
 
+/// Returns the response for tokens
+/// which cannot be handled because `what` is not configured.
+fn not_configured(what: &str) -> Response {
+    warn!("Rejecting token, {what} is not configured.");
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        format!("{what} is not configured on this server"),
+    )
+        .into_response()
+}
+
+/// Returns 429 Too Many Requests
+/// asking the client to retry after `delay`.
+pub(crate) fn rate_limited(provider: &str, delay: Duration) -> Response {
+    warn!("Rejecting notification, {provider} rate limit is exceeded.");
+    let retry_after = delay.as_secs_f64().ceil().max(1.0).to_string();
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after)],
+    )
+        .into_response()
+}
+
+/// Decrypts an OpenPGP-encrypted token
+/// and records which key decrypted it.
+///
+/// Tokens encrypted to retired keys are rejected.
+fn decrypt_token(state: &State, decryptor: &PgpDecryptor, message: &str) -> Result<String> {
+    let decrypted = decryptor.decrypt(message)?;
+    if decrypted.cached {
+        state.metrics().openpgp_cache_hits_total.inc();
+    } else {
+        state.metrics().openpgp_cache_misses_total.inc();
+    }
+    state
+        .metrics()
+        .openpgp_decryptions_total
+        .get_or_create(&vec![
+            ("fingerprint".to_string(), decrypted.fingerprint.clone()),
+            ("status".to_string(), decrypted.status.as_str().to_string()),
+        ])
+        .inc();
+    match decrypted.status {
+        KeyStatus::Active => {}
+        KeyStatus::Deprecated => warn!(
+            "Token is encrypted to deprecated OpenPGP key {}.",
+            decrypted.fingerprint
+        ),
+        KeyStatus::Retired => bail!(
+            "Token is encrypted to retired OpenPGP key {}",
+            decrypted.fingerprint
+        ),
+    }
+    Ok(decrypted.token)
+}
+
+/// Returns 410 Gone for a token which cannot be decrypted
+/// or is rejected by the token policy,
+/// so the email server can remove the token.
+fn decryption_failed(state: &State, err: &Error) -> Response {
+    error!("Failed to decrypt device token: {:#}.", err);
+    state.metrics().openpgp_decryption_failures_total.inc();
+    StatusCode::GONE.into_response()
+}
+
+#[derive(Debug, Serialize)]
+struct PublicKeys {
+    keys: Vec<PublicKey>,
+}
+
+/// Returns the public keys which tokens can be encrypted to,
+/// so clients can pin them and follow key rotations.
+async fn openpgp_key(axum::extract::State(state): axum::extract::State<State>) -> Response {
+    let Some(decryptor) = state.openpgp_decryptor() else {
+        return (
+            StatusCode::NOT_IMPLEMENTED,
+            "OpenPGP is not configured on this server",
+        )
+            .into_response();
+    };
+    axum::Json(PublicKeys {
+        keys: decryptor.public_keys(),
+    })
+    .into_response()
+}
+
 /// Registers a device for heartbeat notifications.
 async fn register_device(
     axum::extract::State(state): axum::extract::State<State>,
     body: String,
-) -> Result<(), AppError> {
+) -> Result<Response, AppError> {
     let query: DeviceQuery = serde_json::from_str(&body)?;
 
     let mut device_token = query.token;
     if let Some(openpgp_device_token) = device_token.strip_prefix("openpgp:") {
-        device_token = state.openpgp_decryptor().decrypt(openpgp_device_token)?;
+        let Some(decryptor) = state.openpgp_decryptor() else {
+            return Ok(not_configured("OpenPGP"));
+        };
+        match decrypt_token(&state, &decryptor, openpgp_device_token) {
+            Ok(decrypted_device_token) => device_token = decrypted_device_token,
+            Err(err) => return Ok(decryption_failed(&state, &err)),
+        }
+    }
+
+    // Only APNS tokens are notified periodically,
+    // other tokens are removed from the schedule when they are due.
+    if let Ok(token) = device_token.parse::<NotificationToken>() {
+        if !state.provider_enabled(token.provider()) {
+            return Ok(not_configured(token.provider()));
+        }
     }
 
     info!("Registering device {:?}.", device_token);
 
     let schedule = state.schedule();
-    schedule.insert_token_now(&device_token)?;
+    schedule.register_token_now(&device_token)?;
 
     // Flush database to ensure we don't lose this token in case of restart.
     schedule.flush().await?;
 
     state.metrics().heartbeat_registrations_total.inc();
 
-    Ok(())
+    Ok(StatusCode::OK.into_response())
 }
 
 pub(crate) enum NotificationToken {
@@ -96,6 +214,21 @@ pub(crate) enum NotificationToken {
     ApnsProduction(String),
 }
 
+/// Names of the push providers returned by [`NotificationToken::provider`].
+pub(crate) const PROVIDERS: [&str; 4] = ["ubports", "fcm", "apns-sandbox", "apns-production"];
+
+impl NotificationToken {
+    /// Returns the name of the push provider for the token.
+    pub(crate) fn provider(&self) -> &'static str {
+        match self {
+            Self::UBports(_) => "ubports",
+            Self::Fcm { .. } => "fcm",
+            Self::ApnsSandbox(_) => "apns-sandbox",
+            Self::ApnsProduction(_) => "apns-production",
+        }
+    }
+}
+
 impl FromStr for NotificationToken {
     type Err = Error;
 
@@ -119,6 +252,32 @@ impl FromStr for NotificationToken {
     }
 }
 
+/// Returns true if the UBports or FCM token
+/// can be safely inserted into a JSON request.
+pub(crate) fn is_valid_token(token: &str) -> bool {
+    token
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':' || c == '-')
+}
+
+/// Builds the request of a visible UBports notification
+/// and returns it together with its body.
+pub(crate) fn ubports_request(
+    client: &reqwest::Client,
+    token: &str,
+) -> (reqwest::RequestBuilder, String) {
+    let url = "https://push.ubports.com/notify";
+    let expire_on = (Local::now() + TimeDelta::weeks(1)).to_rfc3339();
+    let body = format!(
+        r#"{{"expire_on":"{expire_on}","appid":"deltatouch.lotharketterer_deltatouch","token":"{token}","data":{{"notification":{{"tag":"sent_by_chatmail_server","card":{{"popup":true,"persist":true,"summary":"New message","body":"You have a new message"}},"sound":true,"vibrate":{{"pattern":[200],"duration":200,"repeat":1}} }},"sent-by":"Chatmail Server"}} }}"#
+    );
+    let request = client
+        .post(url)
+        .body(body.clone())
+        .header("Content-Type", "application/json");
+    (request, body)
+}
+
 /// Notify the UBports push server
 ///
 /// API documentation is available at
@@ -128,24 +287,12 @@ async fn notify_ubports(
     token: &str,
     metrics: &Metrics,
 ) -> Result<StatusCode> {
-    if !token
-        .chars()
-        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':' || c == '-')
-    {
+    if !is_valid_token(token) {
         return Ok(StatusCode::GONE);
     }
 
-    let url = "https://push.ubports.com/notify";
-    let expire_on = (Local::now() + TimeDelta::weeks(1)).to_rfc3339();
-    let body = format!(
-        r#"{{"expire_on":"{expire_on}","appid":"deltatouch.lotharketterer_deltatouch","token":"{token}","data":{{"notification":{{"tag":"sent_by_chatmail_server","card":{{"popup":true,"persist":true,"summary":"New message","body":"You have a new message"}},"sound":true,"vibrate":{{"pattern":[200],"duration":200,"repeat":1}} }},"sent-by":"Chatmail Server"}} }}"#
-    );
-    let res = client
-        .post(url)
-        .body(body.clone())
-        .header("Content-Type", "application/json")
-        .send()
-        .await?;
+    let (request, body) = ubports_request(client, token);
+    let res = request.send().await?;
     let status = res.status();
     if status.is_client_error() {
         warn!("Failed to deliver UBports notification to {token}");
@@ -162,6 +309,24 @@ async fn notify_ubports(
     Ok(StatusCode::OK)
 }
 
+/// Builds the request of an FCM notification
+/// and returns it together with its body.
+pub(crate) fn fcm_request(
+    client: &reqwest::Client,
+    fcm_api_key: &str,
+    token: &str,
+) -> (reqwest::RequestBuilder, String) {
+    let url = "https://fcm.googleapis.com/v1/projects/delta-chat-fcm/messages:send";
+    let body =
+        format!("{{\"message\":{{\"token\":\"{token}\",\"data\":{{\"level\": \"awesome\"}} }} }}");
+    let request = client
+        .post(url)
+        .body(body.clone())
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {fcm_api_key}"));
+    (request, body)
+}
+
 /// Notifies a single FCM token.
 ///
 /// API documentation is available at
@@ -178,23 +343,12 @@ async fn notify_fcm(
         return Ok(StatusCode::INTERNAL_SERVER_ERROR);
     };
 
-    if !token
-        .chars()
-        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':' || c == '-')
-    {
+    if !is_valid_token(token) {
         return Ok(StatusCode::GONE);
     }
 
-    let url = "https://fcm.googleapis.com/v1/projects/delta-chat-fcm/messages:send";
-    let body =
-        format!("{{\"message\":{{\"token\":\"{token}\",\"data\":{{\"level\": \"awesome\"}} }} }}");
-    let res = client
-        .post(url)
-        .body(body.clone())
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {fcm_api_key}"))
-        .send()
-        .await?;
+    let (request, body) = fcm_request(client, fcm_api_key, token);
+    let res = request.send().await?;
     let status = res.status();
     if status.is_client_error() {
         warn!("Failed to deliver FCM notification to {token}");
@@ -211,9 +365,9 @@ async fn notify_fcm(
     Ok(StatusCode::OK)
 }
 
-async fn notify_apns(state: State, client: a2::Client, device_token: String) -> Result<StatusCode> {
-    let schedule = state.schedule();
-    let payload = DefaultNotificationBuilder::new()
+/// Builds the payload of a visible APNS notification.
+pub(crate) fn alert_payload<'a>(device_token: &'a str, topic: Option<&'a str>) -> Payload<'a> {
+    DefaultNotificationBuilder::new()
         .set_title("New messages")
         .set_title_loc_key("new_messages") // Localization key for the title.
         .set_body("You have new messages")
@@ -221,16 +375,22 @@ async fn notify_apns(state: State, client: a2::Client, device_token: String) ->
         .set_sound("default")
         .set_mutable_content()
         .build(
-            &device_token,
+            device_token,
             NotificationOptions {
                 // High priority (10).
                 // <https://developer.apple.com/documentation/usernotifications/sending-notification-requests-to-apns>
                 apns_priority: Some(Priority::High),
-                apns_topic: state.topic(),
+                apns_topic: topic,
                 apns_push_type: Some(PushType::Alert),
                 ..Default::default()
             },
-        );
+        )
+}
+
+async fn notify_apns(state: State, client: a2::Client, device_token: String) -> Result<StatusCode> {
+    let schedule = state.schedule();
+    let topic = state.topic();
+    let payload = alert_payload(&device_token, topic.as_deref());
 
     match client.send(payload).await {
         Ok(res) => {
@@ -273,60 +433,177 @@ async fn notify_apns(state: State, client: a2::Client, device_token: String) ->
 async fn notify_device(
     axum::extract::State(state): axum::extract::State<State>,
     mut device_token: String,
-) -> Result<StatusCode, AppError> {
+) -> Result<Response, AppError> {
     // Decrypt the token if it is OpenPGP-encrypted.
     if let Some(openpgp_device_token) = device_token.strip_prefix("openpgp:") {
-        match state.openpgp_decryptor().decrypt(openpgp_device_token) {
+        let Some(decryptor) = state.openpgp_decryptor() else {
+            return Ok(not_configured("OpenPGP"));
+        };
+        match decrypt_token(&state, &decryptor, openpgp_device_token) {
             Ok(decrypted_device_token) => {
                 device_token = decrypted_device_token;
             }
-            Err(err) => {
-                error!("Failed to decrypt device token: {:#}.", err);
-
-                let metrics = state.metrics();
-                metrics.openpgp_decryption_failures_total.inc();
-
-                // Return 410 Gone response so email server can remove the token.
-                return Ok(StatusCode::GONE);
-            }
+            Err(err) => return Ok(decryption_failed(&state, &err)),
         }
     }
 
     info!("Got direct notification for {device_token}.");
     let device_token: NotificationToken = device_token.as_str().parse()?;
+    if !state.provider_enabled(device_token.provider()) {
+        return Ok(not_configured(device_token.provider()));
+    }
+    let provider = device_token.provider();
+    if let Err(delay) = state
+        .rate_limiter()
+        .try_acquire(provider, ratelimit::Priority::Direct)
+    {
+        return Ok(rate_limited(provider, delay));
+    }
 
-    let status_code = match device_token {
-        NotificationToken::UBports(token) => {
-            let client = state.fcm_client().clone();
-            let metrics = state.metrics();
-            notify_ubports(&client, &token, metrics).await?
-        }
-        NotificationToken::Fcm {
-            package_name,
-            token,
-        } => {
-            let client = state.fcm_client().clone();
-            let Ok(fcm_token) = state.fcm_token().await else {
-                return Ok(StatusCode::INTERNAL_SERVER_ERROR);
-            };
-            let metrics = state.metrics();
-            notify_fcm(
-                &client,
-                fcm_token.as_deref(),
-                &package_name,
-                &token,
-                metrics,
-            )
-            .await?
-        }
-        NotificationToken::ApnsSandbox(token) => {
-            let client = state.sandbox_client().clone();
-            notify_apns(state, client, token).await?
-        }
-        NotificationToken::ApnsProduction(token) => {
-            let client = state.production_client().clone();
-            notify_apns(state, client, token).await?
+    let result = async {
+        let status_code = match device_token {
+            NotificationToken::UBports(token) => {
+                let client = state.fcm_client().clone();
+                let metrics = state.metrics();
+                notify_ubports(&client, &token, metrics).await?
+            }
+            NotificationToken::Fcm {
+                package_name,
+                token,
+            } => {
+                let client = state.fcm_client().clone();
+                let Ok(fcm_token) = state.fcm_token().await else {
+                    return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+                };
+                let metrics = state.metrics();
+                notify_fcm(
+                    &client,
+                    fcm_token.as_deref(),
+                    &package_name,
+                    &token,
+                    metrics,
+                )
+                .await?
+            }
+            NotificationToken::ApnsSandbox(token) => {
+                let client = state.sandbox_client().context("APNS is not configured")?;
+                notify_apns(state.clone(), client, token).await?
+            }
+            NotificationToken::ApnsProduction(token) => {
+                let client = state
+                    .production_client()
+                    .context("APNS is not configured")?;
+                notify_apns(state.clone(), client, token).await?
+            }
+        };
+        Ok::<_, Error>(status_code)
+    }
+    .await;
+
+    match result {
+        Ok(StatusCode::OK) => state.record_delivery(provider, Ok(())),
+        Ok(StatusCode::GONE) => {}
+        Ok(status_code) => state.record_delivery(provider, Err(status_code.to_string())),
+        Err(ref err) => state.record_delivery(provider, Err(format!("{err:#}"))),
+    }
+    Ok(result?.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    use tempfile::tempdir;
+
+    use crate::config::Config;
+    use crate::openpgp::generate_key;
+    use crate::schedule::Schedule;
+    use crate::storage::MemoryStorage;
+
+    /// Starts the server on a random port
+    /// and returns its URL.
+    async fn serve(config: &Config) -> Result<String> {
+        let schedule = Schedule::with_storage(Box::<MemoryStorage>::default());
+        let state = State::new(schedule, Metrics::new(), config).await?;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let app = router(state);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Ok(url)
+    }
+
+    #[tokio::test]
+    async fn test_openpgp_key_not_configured() -> Result<()> {
+        let url = serve(&Config::default()).await?;
+        let res = reqwest::get(format!("{url}/openpgp-key")).await?;
+        assert_eq!(res.status(), StatusCode::NOT_IMPLEMENTED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_openpgp_key() -> Result<()> {
+        let keyring = [
+            generate_key("deprecated@example.org", None)?,
+            generate_key("retired@example.org", None)?,
+            generate_key("active@example.org", None)?,
+        ]
+        .join("\n");
+        let fingerprints: Vec<String> = PgpDecryptor::new(&keyring, &[], &[], &BTreeMap::new())?
+            .public_keys()
+            .into_iter()
+            .map(|key| key.fingerprint)
+            .collect();
+
+        let dir = tempdir()?;
+        let keyring_path = dir.path().join("keyring.asc");
+        std::fs::write(&keyring_path, &keyring)?;
+        let config = Config {
+            openpgp_keyring_path: Some(keyring_path),
+            openpgp_deprecated_keys: vec![fingerprints[0].clone()],
+            openpgp_retired_keys: vec![fingerprints[1].clone()],
+            ..Config::default()
+        };
+        let url = serve(&config).await?;
+
+        let res = reqwest::get(format!("{url}/openpgp-key")).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&res.text().await?)?;
+        let keys = body["keys"].as_array().context("No keys")?;
+
+        // Active keys are published first, retired keys are not published.
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0]["fingerprint"], fingerprints[2].as_str());
+        assert_eq!(keys[0]["status"], "active");
+        assert_eq!(keys[1]["fingerprint"], fingerprints[0].as_str());
+        assert_eq!(keys[1]["status"], "deprecated");
+        for key in keys {
+            let armored = key["armored"].as_str().context("No armored key")?;
+            assert!(armored.starts_with("-----BEGIN PGP PUBLIC KEY BLOCK-----"));
         }
-    };
-    Ok(status_code)
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_register_rejected() -> Result<()> {
+        let keyring = generate_key("active@example.org", None)?;
+        let dir = tempdir()?;
+        let keyring_path = dir.path().join("keyring.asc");
+        std::fs::write(&keyring_path, &keyring)?;
+        let config = Config {
+            openpgp_keyring_path: Some(keyring_path),
+            ..Config::default()
+        };
+        let url = serve(&config).await?;
+
+        // Tokens which cannot be decrypted are gone like for `/notify`.
+        let res = reqwest::Client::new()
+            .post(format!("{url}/register"))
+            .body(serde_json::json!({ "token": "openpgp:invalid" }).to_string())
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::GONE);
+        Ok(())
+    }
 }
@@ -1,14 +1,21 @@
-use std::io::{Read, Seek};
-use std::path::Path;
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::io::{Cursor, Seek};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, SystemTime};
 
 use a2::{Client, Endpoint};
 use anyhow::{Context as _, Result};
+use log::*;
+use serde::Serialize;
+use tokio::sync::watch;
 
+use crate::config::Config;
+use crate::expiry;
 use crate::metrics::Metrics;
-use crate::openpgp::PgpDecryptor;
+use crate::openpgp::{DecryptionCache, PgpDecryptor};
+use crate::ratelimit::RateLimiter;
 use crate::schedule::Schedule;
+use crate::server::PROVIDERS;
 
 #[derive(Clone)]
 pub struct State {
@@ -20,76 +27,273 @@ pub struct InnerState {
 
     fcm_client: reqwest::Client,
 
-    production_client: Client,
-
-    sandbox_client: Client,
-
-    topic: Option<String>,
-
     metrics: Metrics,
 
     /// Heartbeat notification interval.
     interval: Duration,
 
-    fcm_authenticator: yup_oauth2::authenticator::DefaultAuthenticator,
+    /// Limits of the notification rate to each provider.
+    ///
+    /// Only its rates are reloaded,
+    /// so reloading does not refill the buckets.
+    rate_limiter: RateLimiter,
+
+    /// Cache of decrypted OpenPGP tokens
+    /// kept when the keyring is reloaded.
+    openpgp_cache: Arc<DecryptionCache>,
+
+    /// Settings which can be reloaded at runtime.
+    reloadable: RwLock<Reloadable>,
+
+    /// Whether heartbeat notifications are paused.
+    paused: watch::Sender<bool>,
+
+    /// Results of recent notifications by provider.
+    health: Mutex<BTreeMap<&'static str, Health>>,
+}
+
+/// Results of recent notifications to a provider.
+#[derive(Debug, Clone, Default)]
+struct Health {
+    last_success: Option<u64>,
+    last_failure: Option<u64>,
+    last_error: Option<String>,
+}
+
+/// Health of a push provider returned by [`State::provider_health`].
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderHealth {
+    pub provider: &'static str,
+
+    pub enabled: bool,
+
+    /// Unix timestamp of the latest delivered notification.
+    pub last_success: Option<u64>,
+
+    /// Unix timestamp of the latest failed notification.
+    pub last_failure: Option<u64>,
+
+    /// Error of the latest failed notification.
+    pub last_error: Option<String>,
+}
+
+/// Part of the state reloaded from the configuration
+/// on SIGHUP and when credential files change.
+struct Reloadable {
+    /// APNS clients, `None` if APNS is not configured.
+    production_client: Option<Client>,
+
+    sandbox_client: Option<Client>,
+
+    /// Expiry time of the APNS certificate as a Unix timestamp.
+    apns_certificate_expiry: Option<i64>,
+
+    topic: Option<String>,
+
+    /// FCM authenticator, `None` if FCM is not configured.
+    fcm_authenticator: Option<Arc<yup_oauth2::authenticator::DefaultAuthenticator>>,
+
+    /// Token authenticating requests to the admin API.
+    admin_token: Option<String>,
 
     /// Decryptor for incoming tokens
-    /// storing the secret keyring inside.
-    openpgp_decryptor: PgpDecryptor,
+    /// storing the secret keyring inside,
+    /// `None` if no keyring is configured.
+    openpgp_decryptor: Option<Arc<PgpDecryptor>>,
+}
+
+impl Reloadable {
+    async fn new(config: &Config, openpgp_cache: &Arc<DecryptionCache>) -> Result<Self> {
+        let (production_client, sandbox_client, apns_certificate_expiry) = match config
+            .certificate_file
+        {
+            Some(ref certificate_file) => {
+                let password = config
+                    .password
+                    .as_ref()
+                    .context("password is required with certificate-file")?
+                    .read()?;
+                let certificate = std::fs::read(certificate_file).context("invalid certificate")?;
+                let expiry = match expiry::certificate_expiry(&certificate, &password) {
+                    Ok(expiry) => Some(expiry),
+                    Err(err) => {
+                        warn!("Failed to read the APNS certificate expiry: {err:#}");
+                        None
+                    }
+                };
+                let mut certificate = Cursor::new(certificate);
+                let production_client =
+                    Client::certificate(&mut certificate, &password, Endpoint::Production)
+                        .context("Failed to create production client")?;
+                certificate.rewind()?;
+                let sandbox_client =
+                    Client::certificate(&mut certificate, &password, Endpoint::Sandbox)
+                        .context("Failed to create sandbox client")?;
+                (Some(production_client), Some(sandbox_client), expiry)
+            }
+            None => (None, None, None),
+        };
+
+        let fcm_authenticator = match config.fcm_key_path {
+            Some(ref fcm_key_path) => {
+                let fcm_key: yup_oauth2::ServiceAccountKey =
+                    yup_oauth2::read_service_account_key(fcm_key_path)
+                        .await
+                        .context("Failed to read key")?;
+                let fcm_authenticator = yup_oauth2::ServiceAccountAuthenticator::builder(fcm_key)
+                    .build()
+                    .await
+                    .context("Failed to create authenticator")?;
+                Some(Arc::new(fcm_authenticator))
+            }
+            None => None,
+        };
+
+        let openpgp_decryptor = PgpDecryptor::from_config(config)?
+            .map(|decryptor| Arc::new(decryptor.with_cache(Arc::clone(openpgp_cache))));
+
+        Ok(Self {
+            production_client,
+            sandbox_client,
+            apns_certificate_expiry,
+            topic: config.topic.clone(),
+            fcm_authenticator,
+            // Checked again as the token file may have changed since validation.
+            admin_token: config.admin_token()?,
+            openpgp_decryptor,
+        })
+    }
 }
 
 impl State {
-    #[allow(clippy::too_many_arguments)]
-    pub async fn new(
-        db: &Path,
-        mut certificate: std::fs::File,
-        password: &str,
-        topic: Option<String>,
-        metrics: Metrics,
-        interval: Duration,
-        fcm_key_path: String,
-        openpgp_keyring_path: String,
-    ) -> Result<Self> {
-        let schedule = Schedule::new(db)?;
+    pub async fn new(schedule: Schedule, metrics: Metrics, config: &Config) -> Result<Self> {
         let fcm_client = reqwest::ClientBuilder::new()
             .timeout(Duration::from_secs(60))
             .build()
             .context("Failed to build FCM client")?;
 
-        let fcm_key: yup_oauth2::ServiceAccountKey =
-            yup_oauth2::read_service_account_key(fcm_key_path)
-                .await
-                .context("Failed to read key")?;
-        let fcm_authenticator = yup_oauth2::ServiceAccountAuthenticator::builder(fcm_key)
-            .build()
-            .await
-            .context("Failed to create authenticator")?;
-
-        let production_client =
-            Client::certificate(&mut certificate, password, Endpoint::Production)
-                .context("Failed to create production client")?;
-        certificate.rewind()?;
-        let sandbox_client = Client::certificate(&mut certificate, password, Endpoint::Sandbox)
-            .context("Failed to create sandbox client")?;
-
-        let mut keyring_file = std::fs::File::open(openpgp_keyring_path)?;
-        let mut keyring = String::new();
-        keyring_file.read_to_string(&mut keyring)?;
-        let openpgp_decryptor = PgpDecryptor::new(&keyring)?;
-
-        Ok(State {
+        let rate_limiter = RateLimiter::new(&config.rate_limits()?);
+        let openpgp_cache = Arc::new(DecryptionCache::new(
+            config.openpgp_cache_size,
+            config.openpgp_cache_ttl,
+        ));
+        let reloadable = Reloadable::new(config, &openpgp_cache).await?;
+
+        let state = State {
             inner: Arc::new(InnerState {
                 schedule,
                 fcm_client,
-                production_client,
-                sandbox_client,
-                topic,
                 metrics,
-                interval,
-                fcm_authenticator,
-                openpgp_decryptor,
+                interval: config.interval,
+                rate_limiter,
+                openpgp_cache,
+                reloadable: RwLock::new(reloadable),
+                paused: watch::Sender::new(false),
+                health: Mutex::new(BTreeMap::new()),
             }),
-        })
+        };
+        state.update_provider_metrics();
+        Ok(state)
+    }
+
+    /// Applies reloadable settings of the configuration.
+    ///
+    /// If any of them fails to load,
+    /// the current settings are kept.
+    pub async fn reload(&self, config: &Config) -> Result<()> {
+        let rate_limits = config.rate_limits()?;
+        let reloadable = Reloadable::new(config, &self.inner.openpgp_cache).await?;
+        *self.inner.reloadable.write().unwrap() = reloadable;
+        self.inner.rate_limiter.set_limits(&rate_limits);
+        self.inner
+            .openpgp_cache
+            .resize(config.openpgp_cache_size, config.openpgp_cache_ttl);
+        log::set_max_level(config.log_level);
+        self.update_provider_metrics();
+        expiry::check(self);
+        Ok(())
+    }
+
+    /// Returns true if notifications can be sent to the provider,
+    /// one of [`PROVIDERS`].
+    pub fn provider_enabled(&self, provider: &str) -> bool {
+        match provider {
+            "ubports" => true,
+            "fcm" => self.reloadable().fcm_authenticator.is_some(),
+            "apns-sandbox" => self.reloadable().sandbox_client.is_some(),
+            "apns-production" => self.reloadable().production_client.is_some(),
+            _ => false,
+        }
+    }
+
+    /// Returns the health of all providers.
+    pub fn provider_health(&self) -> Vec<ProviderHealth> {
+        let health = self.inner.health.lock().unwrap();
+        PROVIDERS
+            .iter()
+            .map(|&provider| {
+                let health = health.get(provider).cloned().unwrap_or_default();
+                ProviderHealth {
+                    provider,
+                    enabled: self.provider_enabled(provider),
+                    last_success: health.last_success,
+                    last_failure: health.last_failure,
+                    last_error: health.last_error,
+                }
+            })
+            .collect()
+    }
+
+    /// Records the result of a notification sent to the provider.
+    ///
+    /// Rejected tokens are not recorded
+    /// as they say nothing about the provider.
+    pub fn record_delivery(&self, provider: &'static str, result: Result<(), String>) {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut health = self.inner.health.lock().unwrap();
+        let health = health.entry(provider).or_default();
+        match result {
+            Ok(()) => health.last_success = Some(now),
+            Err(err) => {
+                health.last_failure = Some(now);
+                health.last_error = Some(err);
+            }
+        }
+    }
+
+    /// Pauses or resumes heartbeat notifications.
+    pub fn set_paused(&self, paused: bool) {
+        self.inner.paused.send_replace(paused);
+    }
+
+    pub fn paused(&self) -> bool {
+        *self.inner.paused.borrow()
+    }
+
+    /// Returns a receiver notified when heartbeat notifications
+    /// are paused or resumed.
+    pub fn subscribe_paused(&self) -> watch::Receiver<bool> {
+        self.inner.paused.subscribe()
+    }
+
+    fn update_provider_metrics(&self) {
+        for provider in PROVIDERS {
+            let enabled = self.provider_enabled(provider);
+            self.metrics()
+                .provider_enabled
+                .get_or_create(&vec![("provider".to_string(), provider.to_string())])
+                .set(i64::from(enabled));
+            if !enabled {
+                info!("Provider {provider} is not configured.");
+            }
+        }
+    }
+
+    fn reloadable(&self) -> RwLockReadGuard<'_, Reloadable> {
+        self.inner.reloadable.read().unwrap()
     }
 
     pub fn schedule(&self) -> &Schedule {
@@ -101,9 +305,12 @@ impl State {
     }
 
     pub async fn fcm_token(&self) -> Result<Option<String>> {
-        let token = self
-            .inner
+        let authenticator = self
+            .reloadable()
             .fcm_authenticator
+            .clone()
+            .context("FCM is not configured")?;
+        let token = authenticator
             .token(&["https://www.googleapis.com/auth/firebase.messaging"])
             .await?
             .token()
@@ -111,16 +318,21 @@ impl State {
         Ok(token)
     }
 
-    pub fn production_client(&self) -> &Client {
-        &self.inner.production_client
+    pub fn production_client(&self) -> Option<Client> {
+        self.reloadable().production_client.clone()
+    }
+
+    pub fn sandbox_client(&self) -> Option<Client> {
+        self.reloadable().sandbox_client.clone()
     }
 
-    pub fn sandbox_client(&self) -> &Client {
-        &self.inner.sandbox_client
+    /// Returns the expiry time of the APNS certificate as a Unix timestamp.
+    pub fn apns_certificate_expiry(&self) -> Option<i64> {
+        self.reloadable().apns_certificate_expiry
     }
 
-    pub fn topic(&self) -> Option<&str> {
-        self.inner.topic.as_deref()
+    pub fn topic(&self) -> Option<String> {
+        self.reloadable().topic.clone()
     }
 
     pub fn metrics(&self) -> &Metrics {
@@ -131,7 +343,17 @@ impl State {
         self.inner.interval
     }
 
-    pub fn openpgp_decryptor(&self) -> &PgpDecryptor {
-        &self.inner.openpgp_decryptor
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.inner.rate_limiter
+    }
+
+    /// Returns the token authenticating requests to the admin API.
+    pub fn admin_token(&self) -> Option<String> {
+        self.reloadable().admin_token.clone()
+    }
+
+    /// Returns the OpenPGP decryptor if a keyring is configured.
+    pub fn openpgp_decryptor(&self) -> Option<Arc<PgpDecryptor>> {
+        self.reloadable().openpgp_decryptor.clone()
     }
 }
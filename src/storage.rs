@@ -0,0 +1,330 @@
+//! Storage backends for the heartbeat schedule.
+//!
+//! [`Schedule`](crate::schedule::Schedule) stores opaque values
+//! keyed by token in one of the backends
+//! implementing the [`Storage`] trait.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use anyhow::{bail, Error, Result};
+use rusqlite::OptionalExtension as _;
+
+/// Iterator over keys and values of a [`Storage`].
+pub type Entries<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// Future returned by [`Storage::flush`].
+pub type Flush<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// Key-value storage of the schedule.
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// Returns the value stored for the key.
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Stores the value for the key, replacing the previous value.
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()>;
+
+    /// Removes the key.
+    fn remove(&self, key: &[u8]) -> Result<()>;
+
+    /// Iterates over all stored keys and values.
+    fn iter(&self) -> Entries<'_>;
+
+    /// Persists all changes on disk.
+    fn flush(&self) -> Flush<'_>;
+}
+
+/// Storage backend selectable on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// [sled](https://sled.rs/) database directory.
+    Sled,
+
+    /// SQLite database file.
+    Sqlite,
+
+    /// Non-persistent storage, mostly useful for testing.
+    Memory,
+}
+
+impl FromStr for Backend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sled" => Ok(Self::Sled),
+            "sqlite" => Ok(Self::Sqlite),
+            "memory" => Ok(Self::Memory),
+            _ => bail!("Unknown database backend {s:?}, expected sled, sqlite or memory"),
+        }
+    }
+}
+
+/// Opens the storage of the given backend at `path`.
+///
+/// `path` is ignored by the in-memory backend.
+pub fn open(backend: Backend, path: &Path) -> Result<Box<dyn Storage>> {
+    Ok(match backend {
+        Backend::Sled => Box::new(SledStorage::open(path)?),
+        Backend::Sqlite => Box::new(SqliteStorage::open(path)?),
+        Backend::Memory => Box::new(MemoryStorage::default()),
+    })
+}
+
+/// Copies all entries from one storage to another.
+///
+/// Returns the number of copied entries.
+pub async fn copy(from: &dyn Storage, to: &dyn Storage) -> Result<usize> {
+    let mut copied = 0;
+    for entry in from.iter() {
+        let (key, value) = entry?;
+        to.insert(&key, &value)?;
+        copied += 1;
+    }
+    to.flush().await?;
+    Ok(copied)
+}
+
+/// Storage in a sled database.
+#[derive(Debug)]
+pub struct SledStorage {
+    db: sled::Db,
+}
+
+impl SledStorage {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = sled::open(path)?;
+        Ok(Self { db })
+    }
+}
+
+impl Storage for SledStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.db.insert(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        self.db.remove(key)?;
+        Ok(())
+    }
+
+    fn iter(&self) -> Entries<'_> {
+        Box::new(self.db.iter().map(|entry| {
+            let (key, value) = entry?;
+            Ok((key.to_vec(), value.to_vec()))
+        }))
+    }
+
+    fn flush(&self) -> Flush<'_> {
+        Box::pin(async move {
+            self.db.flush_async().await?;
+            Ok(())
+        })
+    }
+}
+
+/// Storage in an SQLite database.
+pub struct SqliteStorage {
+    conn: Mutex<rusqlite::Connection>,
+}
+
+impl fmt::Debug for SqliteStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqliteStorage").finish_non_exhaustive()
+    }
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = rusqlite::Connection::open(path)?;
+        // Each change is durable once it is committed,
+        // so `flush` does not need to block the connection.
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "FULL")?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS tokens (key BLOB PRIMARY KEY, value BLOB NOT NULL)",
+            (),
+        )?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Returns the next batch of entries with keys greater than `after`.
+    fn batch(&self, after: Option<&[u8]>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(
+            "SELECT key, value FROM tokens WHERE ?1 IS NULL OR key > ?1 ORDER BY key LIMIT 1000",
+        )?;
+        let entries = stmt
+            .query_map((after,), |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(entries)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let conn = self.conn.lock().unwrap();
+        let value = conn
+            .prepare_cached("SELECT value FROM tokens WHERE key=?")?
+            .query_row((key,), |row| row.get(0))
+            .optional()?;
+        Ok(value)
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.prepare_cached("INSERT OR REPLACE INTO tokens (key, value) VALUES (?, ?)")?
+            .execute((key, value))?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.prepare_cached("DELETE FROM tokens WHERE key=?")?
+            .execute((key,))?;
+        Ok(())
+    }
+
+    fn iter(&self) -> Entries<'_> {
+        // Entries are read in batches
+        // to avoid holding the connection lock for the whole iteration.
+        let mut last_key: Option<Vec<u8>> = None;
+        let mut batch = Vec::new().into_iter();
+        Box::new(std::iter::from_fn(move || {
+            if batch.len() == 0 {
+                let entries = match self.batch(last_key.as_deref()) {
+                    Ok(entries) => entries,
+                    Err(err) => return Some(Err(err)),
+                };
+                batch = entries.into_iter();
+            }
+            let (key, value) = batch.next()?;
+            last_key = Some(key.clone());
+            Some(Ok((key, value)))
+        }))
+    }
+
+    fn flush(&self) -> Flush<'_> {
+        // Changes are synced on commit
+        // and the WAL is checkpointed automatically.
+        Box::pin(std::future::ready(Ok(())))
+    }
+}
+
+/// Non-persistent storage in memory.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    entries: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.entries.lock().unwrap().get(key).cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn iter(&self) -> Entries<'_> {
+        let entries: Vec<_> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect();
+        Box::new(entries.into_iter())
+    }
+
+    fn flush(&self) -> Flush<'_> {
+        Box::pin(std::future::ready(Ok(())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    async fn check_storage(storage: &dyn Storage) -> Result<()> {
+        assert_eq!(storage.get(b"foo")?, None);
+        storage.insert(b"foo", b"1")?;
+        storage.insert(b"bar", b"2")?;
+        storage.insert(b"foo", b"3")?;
+        assert_eq!(storage.get(b"foo")?, Some(b"3".to_vec()));
+
+        storage.remove(b"bar")?;
+        storage.remove(b"baz")?;
+        let entries = storage.iter().collect::<Result<Vec<_>>>()?;
+        assert_eq!(entries, vec![(b"foo".to_vec(), b"3".to_vec())]);
+
+        storage.flush().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_backends() -> Result<()> {
+        let dir = tempdir()?;
+        check_storage(&SledStorage::open(&dir.path().join("db.sled"))?).await?;
+        check_storage(&SqliteStorage::open(&dir.path().join("db.sqlite"))?).await?;
+        check_storage(&MemoryStorage::default()).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_copy() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("db.sqlite");
+        {
+            let from = MemoryStorage::default();
+            from.insert(b"foo", b"1")?;
+            from.insert(b"bar", b"2")?;
+            let to = SqliteStorage::open(&db_path)?;
+            assert_eq!(copy(&from, &to).await?, 2);
+        }
+
+        let storage = SqliteStorage::open(&db_path)?;
+        assert_eq!(storage.get(b"bar")?, Some(b"2".to_vec()));
+        assert_eq!(storage.iter().count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlite_batches() -> Result<()> {
+        let dir = tempdir()?;
+        let storage = SqliteStorage::open(&dir.path().join("db.sqlite"))?;
+        for i in 0..2500u32 {
+            storage.insert(&i.to_be_bytes(), b"")?;
+        }
+        let keys = storage
+            .iter()
+            .map(|entry| Ok(entry?.0))
+            .collect::<Result<Vec<_>>>()?;
+        let expected: Vec<_> = (0..2500u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        assert_eq!(keys, expected);
+        Ok(())
+    }
+}